@@ -0,0 +1,3 @@
+DELETE FROM `batches`;
+ALTER TABLE `batches` ADD COLUMN `bsos` LONGTEXT NOT NULL;
+DROP TABLE IF EXISTS `batch_bsos`;
@@ -0,0 +1,20 @@
+-- Pending batch items, one row per BSO (deduplicated by id), replacing the
+-- newline-joined JSON previously stored in `batches`.`bsos`.
+-- NOTE: any in flight batches are dropped, clients will restart them.
+CREATE TABLE IF NOT EXISTS `batch_bsos`(
+    `userid` BIGINT                         NOT NULL,
+    `collection` INT                        NOT NULL,
+    `batch_id` BIGINT                       NOT NULL,
+    `id` VARCHAR(64)                        NOT NULL,
+
+    -- NULL columns are left untouched when the batch is committed
+    `sortindex` INT,
+    `payload` MEDIUMTEXT,
+    -- ttl in seconds
+    `ttl` BIGINT,
+
+    PRIMARY KEY (`userid`, `collection`, `batch_id`, `id`)
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
+
+DELETE FROM `batches`;
+ALTER TABLE `batches` DROP COLUMN `bsos`;
@@ -5,6 +5,7 @@ pub mod mock;
 pub mod mysql;
 pub mod params;
 pub mod results;
+pub mod slow_query;
 pub mod spanner;
 #[cfg(test)]
 mod tests;
@@ -17,7 +18,7 @@ use async_trait::async_trait;
 use cadence::{Gauged, StatsdClient};
 use futures::future::{self, LocalBoxFuture, TryFutureExt};
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 pub use self::error::{DbError, DbErrorKind};
@@ -25,6 +26,7 @@ use self::util::SyncTimestamp;
 use crate::error::{ApiError, ApiResult};
 use crate::server::metrics::Metrics;
 use crate::settings::Settings;
+use crate::tracing::SpanContext;
 use crate::web::extractors::HawkIdentifier;
 
 lazy_static! {
@@ -71,6 +73,9 @@ pub trait DbPool: Sync + Send + Debug {
     fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), DbError>;
 
     fn box_clone(&self) -> Box<dyn DbPool>;
+
+    /// Close the pool's idle connections on shutdown
+    async fn close(&self) {}
 }
 
 impl Clone for Box<dyn DbPool> {
@@ -159,12 +164,25 @@ pub trait Db<'a>: Debug + 'a {
 
     fn get_batch(&self, params: params::GetBatch) -> DbFuture<'_, Option<results::GetBatch>>;
 
+    fn get_batch_usage(
+        &self,
+        params: params::GetBatchUsage,
+    ) -> DbFuture<'_, results::GetBatchUsage>;
+
     fn commit_batch(&self, params: params::CommitBatch) -> DbFuture<'_, results::CommitBatch>;
 
     fn box_clone(&self) -> Box<dyn Db<'a>>;
 
     fn check(&self) -> DbFuture<'_, results::Check>;
 
+    /// The version of the db's schema, when managed by migrations
+    fn schema_version(&self) -> DbFuture<'_, Option<results::SchemaVersion>> {
+        Box::pin(future::ok(None))
+    }
+
+    /// Set the parent of the spans of subsequent db calls
+    fn set_span_context(&self, _context: Option<SpanContext>) {}
+
     /// Retrieve the timestamp for an item/collection
     ///
     /// Modeled on the Python `get_resource_timestamp` function.
@@ -244,7 +262,7 @@ impl<'a> Clone for Box<dyn Db<'a>> {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Sorting {
     None,
@@ -259,6 +277,39 @@ impl Default for Sorting {
     }
 }
 
+/// The lifecycle of a pool's connections, per the `database_pool_*` settings
+#[derive(Clone, Copy, Debug)]
+pub struct PoolTimeouts {
+    /// How long to wait to check out a connection
+    pub connection_timeout: Duration,
+    /// How long a connection may sit idle, None for indefinitely
+    pub idle_timeout: Option<Duration>,
+    /// How long a connection may be reused, None for indefinitely
+    pub max_lifetime: Option<Duration>,
+    /// Whether connections are validated when checked out
+    pub test_on_checkout: bool,
+}
+
+impl PoolTimeouts {
+    pub fn new(settings: &Settings) -> Self {
+        let optional = |secs: u32| {
+            if secs > 0 {
+                Some(Duration::from_secs(secs.into()))
+            } else {
+                None
+            }
+        };
+        Self {
+            connection_timeout: Duration::from_secs(
+                settings.database_pool_connection_timeout_secs.into(),
+            ),
+            idle_timeout: optional(settings.database_pool_idle_timeout_secs),
+            max_lifetime: optional(settings.database_pool_max_lifetime_secs),
+            test_on_checkout: settings.database_pool_test_on_checkout,
+        }
+    }
+}
+
 /// Create/initialize a pool of managed Db connections
 pub async fn pool_from_settings(
     settings: &Settings,
@@ -288,6 +339,7 @@ pub fn spawn_pool_periodic_reporter(
             let results::PoolState {
                 connections,
                 idle_connections,
+                ..
             } = pool.state();
             metrics
                 .gauge_with_tags(
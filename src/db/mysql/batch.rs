@@ -3,13 +3,14 @@ use diesel::{
     dsl::sql,
     insert_into,
     result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
-    sql_types::Integer,
-    update, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, TextExpressionMethods,
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
 };
 
 use super::{
-    models::{MysqlDb, Result},
-    schema::batches,
+    models::{MysqlDb, Result, COLLECTION_ID, DEFAULT_BSO_TTL, EXPIRY, MODIFIED, USER_ID},
+    schema::{batch_bsos, batches},
 };
 use crate::db::{params, results, DbError, DbErrorKind, BATCH_LIFETIME};
 
@@ -17,23 +18,24 @@ pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::Crea
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
     let timestamp = db.timestamp().as_i64();
-    let bsos = bsos_to_batch_string(&params.bsos)?;
-    insert_into(batches::table)
-        .values((
-            batches::user_id.eq(&user_id),
-            batches::collection_id.eq(&collection_id),
-            batches::id.eq(&timestamp),
-            batches::bsos.eq(&bsos),
-            batches::expiry.eq(timestamp + BATCH_LIFETIME),
-        ))
-        .execute(&db.conn)
-        .map_err(|e| -> DbError {
-            match e {
-                // The user tried to create two batches with the same timestamp
-                DieselError::DatabaseError(UniqueViolation, _) => DbErrorKind::Conflict.into(),
-                _ => e.into(),
-            }
-        })?;
+    db.conn.transaction(|| {
+        insert_into(batches::table)
+            .values((
+                batches::user_id.eq(&user_id),
+                batches::collection_id.eq(&collection_id),
+                batches::id.eq(&timestamp),
+                batches::expiry.eq(timestamp + BATCH_LIFETIME),
+            ))
+            .execute(&db.conn)
+            .map_err(|e| -> DbError {
+                match e {
+                    // The user tried to create two batches with the same timestamp
+                    DieselError::DatabaseError(UniqueViolation, _) => DbErrorKind::Conflict.into(),
+                    _ => e.into(),
+                }
+            })?;
+        do_append(db, user_id, collection_id, timestamp, params.bsos)
+    })?;
     Ok(encode_id(timestamp))
 }
 
@@ -56,25 +58,24 @@ pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<()> {
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
-    let bsos = bsos_to_batch_string(&params.bsos)?;
-    let affected_rows = update(batches::table)
-        .filter(batches::user_id.eq(&user_id))
-        .filter(batches::collection_id.eq(&collection_id))
-        .filter(batches::id.eq(&id))
-        .filter(batches::expiry.gt(&db.timestamp().as_i64()))
-        .set(batches::bsos.eq(batches::bsos.concat(&bsos)))
-        .execute(&db.conn)?;
-    if affected_rows == 1 {
-        Ok(())
-    } else {
-        Err(DbErrorKind::BatchNotFound.into())
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.id,
+        },
+    )?;
+    if !exists {
+        Err(DbErrorKind::BatchNotFound)?
     }
+    db.conn
+        .transaction(|| do_append(db, user_id, collection_id, id, params.bsos))
 }
 
 #[derive(Debug, Default, Queryable)]
 pub struct Batch {
     pub id: i64,
-    pub bsos: String,
     pub expiry: i64,
 }
 
@@ -83,7 +84,7 @@ pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::Get
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
     Ok(batches::table
-        .select((batches::id, batches::bsos, batches::expiry))
+        .select((batches::id, batches::expiry))
         .filter(batches::user_id.eq(&user_id))
         .filter(batches::collection_id.eq(&collection_id))
         .filter(batches::id.eq(&id))
@@ -92,15 +93,46 @@ pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::Get
         .optional()?
         .map(|batch| results::GetBatch {
             id: encode_id(batch.id),
-            bsos: batch.bsos,
+            // The pending items live in batch_bsos: bsos is only kept for
+            // compat. with params::Batch
+            bsos: "".to_owned(),
             expiry: batch.expiry,
         }))
 }
 
+pub fn get_usage(db: &MysqlDb, params: params::GetBatchUsage) -> Result<results::GetBatchUsage> {
+    let id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let count = batch_bsos::table
+        .select(sql::<BigInt>("COUNT(*)"))
+        .filter(batch_bsos::user_id.eq(&user_id))
+        .filter(batch_bsos::collection_id.eq(&collection_id))
+        .filter(batch_bsos::batch_id.eq(&id))
+        .filter(batch_bsos::id.ne_all(params.excluding))
+        .get_result::<i64>(&db.conn)?;
+    let total_bytes = batch_bsos::table
+        .select(sql::<Nullable<BigInt>>("SUM(LENGTH(payload))"))
+        .filter(batch_bsos::user_id.eq(&user_id))
+        .filter(batch_bsos::collection_id.eq(&collection_id))
+        .filter(batch_bsos::batch_id.eq(&id))
+        .filter(batch_bsos::id.ne_all(params.excluding_payloads))
+        .get_result::<Option<i64>>(&db.conn)?;
+    Ok(results::GetBatchUsage {
+        count: count as u64,
+        total_bytes: total_bytes.unwrap_or_default() as u64,
+    })
+}
+
 pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
     let id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    diesel::delete(batch_bsos::table)
+        .filter(batch_bsos::user_id.eq(&user_id))
+        .filter(batch_bsos::collection_id.eq(&collection_id))
+        .filter(batch_bsos::batch_id.eq(&id))
+        .execute(&db.conn)?;
     diesel::delete(batches::table)
         .filter(batches::user_id.eq(&user_id))
         .filter(batches::collection_id.eq(&collection_id))
@@ -111,24 +143,100 @@ pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
 
 /// Commits a batch to the bsos table, deleting the batch when succesful
 pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::CommitBatch> {
-    let bsos = batch_string_to_bsos(&params.batch.bsos)?;
+    let id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_or_create_collection_id(&params.collection)?;
+    let timestamp = db.timestamp().as_i64();
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.sql.apply_batch", None);
-    let result = db.post_bsos_sync(params::PostBsos {
-        user_id: params.user_id.clone(),
-        collection: params.collection.clone(),
-        bsos,
+
+    // Apply every pending item in one statement: new rows are inserted,
+    // existing rows only have the fields set in the batch updated (matching
+    // put_bso's semantics)
+    let apply = format!(
+        r#"
+        INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+        SELECT ?, ?, id, sortindex, COALESCE(payload, ''), ?, ? + COALESCE(ttl, ?) * 1000
+          FROM batch_bsos
+         WHERE {user_id} = ?
+           AND {collection_id} = ?
+           AND batch_id = ?
+            ON DUPLICATE KEY UPDATE
+               sortindex = COALESCE(batch_bsos.sortindex, bso.sortindex),
+               payload = COALESCE(batch_bsos.payload, bso.payload),
+               {modified} = IF(batch_bsos.payload IS NULL AND batch_bsos.sortindex IS NULL,
+                               bso.{modified}, VALUES({modified})),
+               {expiry} = IF(batch_bsos.ttl IS NULL, bso.{expiry}, VALUES({expiry}))
+        "#,
+        user_id = USER_ID,
+        collection_id = COLLECTION_ID,
+        modified = MODIFIED,
+        expiry = EXPIRY
+    );
+    let modified = db.conn.transaction(|| {
+        sql_query(apply)
+            .bind::<BigInt, _>(user_id)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(i64::from(DEFAULT_BSO_TTL))
+            .bind::<BigInt, _>(user_id)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(id)
+            .execute(&db.conn)?;
+        let modified = db.touch_collection(user_id as u32, collection_id)?;
+        delete(
+            db,
+            params::DeleteBatch {
+                user_id: params.user_id,
+                collection: params.collection,
+                id: params.batch.id,
+            },
+        )?;
+        Ok(modified)
+    })?;
+    // XXX: returning results::PostBsos here isn't needed
+    Ok(results::PostBsos {
+        modified,
+        success: Default::default(),
         failed: Default::default(),
-    });
-    delete(
-        db,
-        params::DeleteBatch {
-            user_id: params.user_id,
-            collection: params.collection,
-            id: params.batch.id,
-        },
-    )?;
-    result
+    })
+}
+
+/// Write bsos to the batch's pending items. Items already pending in the
+/// batch are merged: only the fields supplied in the newer item overwrite
+/// them
+fn do_append(
+    db: &MysqlDb,
+    user_id: i64,
+    collection_id: i32,
+    batch_id: i64,
+    bsos: Vec<params::PostCollectionBso>,
+) -> Result<()> {
+    let upsert = format!(
+        r#"
+        INSERT INTO batch_bsos ({user_id}, {collection_id}, batch_id, id, sortindex, payload, ttl)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE
+               sortindex = COALESCE(VALUES(sortindex), sortindex),
+               payload = COALESCE(VALUES(payload), payload),
+               ttl = COALESCE(VALUES(ttl), ttl)
+        "#,
+        user_id = USER_ID,
+        collection_id = COLLECTION_ID
+    );
+    for bso in bsos {
+        sql_query(upsert.as_str())
+            .bind::<BigInt, _>(user_id)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<BigInt, _>(batch_id)
+            .bind::<Text, _>(&bso.id)
+            .bind::<Nullable<Integer>, _>(bso.sortindex)
+            .bind::<Nullable<Text>, _>(bso.payload.as_deref())
+            .bind::<Nullable<BigInt>, _>(bso.ttl.map(i64::from))
+            .execute(&db.conn)?;
+    }
+    Ok(())
 }
 
 pub fn validate_batch_id(id: &str) -> Result<()> {
@@ -147,36 +255,6 @@ fn decode_id(id: &str) -> Result<i64> {
         .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
 }
 
-/// Deserialize a batch string into bsos
-fn batch_string_to_bsos(bsos: &str) -> Result<Vec<params::PostCollectionBso>> {
-    bsos.lines()
-        .map(|line| {
-            serde_json::from_str(line).map_err(|e| {
-                DbError::internal(&format!("Couldn't deserialize batch::load_bsos bso: {}", e))
-            })
-        })
-        .collect()
-}
-
-/// Serialize bsos into strings separated by newlines
-fn bsos_to_batch_string(bsos: &[params::PostCollectionBso]) -> Result<String> {
-    let batch_strings: Result<Vec<String>> = bsos
-        .iter()
-        .map(|bso| {
-            serde_json::to_string(bso).map_err(|e| {
-                DbError::internal(&format!("Couldn't serialize batch::create bso: {}", e))
-            })
-        })
-        .collect();
-    batch_strings.map(|bs| {
-        format!(
-            "{}{}",
-            bs.join("\n"),
-            if bsos.is_empty() { "" } else { "\n" }
-        )
-    })
-}
-
 #[macro_export]
 macro_rules! batch_db_method {
     ($name:ident, $batch_name:ident, $type:ident) => {
@@ -13,7 +13,8 @@ use diesel::{
     r2d2::{ConnectionManager, PooledConnection},
     sql_query,
     sql_types::{BigInt, Integer, Nullable, Text},
-    Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, GroupByDsl, OptionalExtension, QueryDsl,
+    RunQueryDsl,
 };
 #[cfg(test)]
 use diesel_logger::LoggingConnection;
@@ -27,11 +28,13 @@ use super::{
 use crate::db::{
     error::{DbError, DbErrorKind},
     params, results,
+    slow_query::SlowQueryLog,
     util::SyncTimestamp,
     Db, DbFuture, Sorting,
 };
 use crate::server::metrics::Metrics;
-use crate::web::extractors::{BsoQueryParams, HawkIdentifier};
+use crate::tracing::{Span, SpanContext, SpanKind};
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
 
 pub type Result<T> = std::result::Result<T, DbError>;
 type Conn = PooledConnection<ConnectionManager<MysqlConnection>>;
@@ -47,6 +50,9 @@ pub const USER_ID: &str = "userid";
 pub const MODIFIED: &str = "modified";
 pub const EXPIRY: &str = "ttl";
 pub const LAST_MODIFIED: &str = "last_modified";
+/// The version of the latest migration (in migrations/), which should be
+/// applied
+pub const LATEST_MIGRATION: &str = "20200612231034";
 
 #[derive(Debug)]
 pub enum CollectionLock {
@@ -66,6 +72,8 @@ struct MysqlDbSession {
     /// Whether a transaction was started (begin() called)
     in_transaction: bool,
     in_write_transaction: bool,
+    /// Parent of the spans of this session's calls
+    span_context: Option<SpanContext>,
 }
 
 #[derive(Clone, Debug)]
@@ -83,6 +91,8 @@ pub struct MysqlDb {
     coll_cache: Arc<CollectionCache>,
 
     pub metrics: Metrics,
+
+    slow_query: SlowQueryLog,
 }
 
 /// Despite the db conn structs being !Sync (see Arc<MysqlDbInner> above) we
@@ -114,7 +124,12 @@ impl Deref for MysqlDb {
 }
 
 impl MysqlDb {
-    pub fn new(conn: Conn, coll_cache: Arc<CollectionCache>, metrics: &Metrics) -> Self {
+    pub fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        slow_query: &SlowQueryLog,
+    ) -> Self {
         let inner = MysqlDbInner {
             #[cfg(not(test))]
             conn,
@@ -126,6 +141,7 @@ impl MysqlDb {
             inner: Arc::new(inner),
             coll_cache,
             metrics: metrics.clone(),
+            slow_query: slow_query.clone(),
         }
     }
 
@@ -196,8 +212,11 @@ impl MysqlDb {
             Err(DbError::internal("Can't escalate read-lock to write-lock"))?
         }
 
-        // Lock the db
-        self.begin(true)?;
+        // Lock the db (reusing the transaction when write locking multiple
+        // collections)
+        if !self.session.borrow().in_write_transaction {
+            self.begin(true)?;
+        }
         let modified = user_collections::table
             .select(user_collections::modified)
             .filter(user_collections::user_id.eq(user_id))
@@ -487,18 +506,30 @@ impl MysqlDb {
             query = query.filter(bso::id.eq_any(ids));
         }
 
+        // Resume immediately after the previous page's last item
+        let keyset = offset.as_ref().and_then(Offset::keyset);
+        if let Some((timestamp, id)) = keyset {
+            let timestamp = timestamp.as_i64();
+            query = match sort {
+                Sorting::Newest => query.filter(
+                    bso::modified
+                        .lt(timestamp)
+                        .or(bso::modified.eq(timestamp).and(bso::id.lt(id.to_owned()))),
+                ),
+                Sorting::Oldest => query.filter(
+                    bso::modified
+                        .gt(timestamp)
+                        .or(bso::modified.eq(timestamp).and(bso::id.gt(id.to_owned()))),
+                ),
+                _ => query,
+            };
+        }
+
         query = match sort {
-            // issue559: Revert to previous sorting
-            /*
-            Sorting::Index => query.order(bso::id.desc()).order(bso::sortindex.desc()),
-            Sorting::Newest | Sorting::None => {
-                query.order(bso::id.desc()).order(bso::modified.desc())
-            }
-            Sorting::Oldest => query.order(bso::id.asc()).order(bso::modified.asc()),
-            */
             Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
+            // The id breaks ties for stable keyset pagination
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
@@ -507,7 +538,11 @@ impl MysqlDb {
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
 
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
+        let numeric_offset = if keyset.is_some() {
+            0
+        } else {
+            offset.as_ref().map_or(0, |offset| offset.offset as i64)
+        };
 
         if numeric_offset != 0 {
             // XXX: copy over this optimization:
@@ -523,7 +558,11 @@ impl MysqlDb {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            Some((limit + numeric_offset).to_string())
+            Some(encode_next_offset(
+                sort,
+                limit + numeric_offset,
+                bsos.last().map(|bso| (bso.modified, bso.id.as_str())),
+            ))
         } else {
             None
         };
@@ -548,7 +587,7 @@ impl MysqlDb {
         } = params.params;
 
         let mut query = bso::table
-            .select(bso::id)
+            .select((bso::id, bso::modified))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
             .filter(bso::expiry.gt(self.timestamp().as_i64()))
@@ -565,10 +604,30 @@ impl MysqlDb {
             query = query.filter(bso::id.eq_any(ids));
         }
 
+        // Resume immediately after the previous page's last item
+        let keyset = offset.as_ref().and_then(Offset::keyset);
+        if let Some((timestamp, id)) = keyset {
+            let timestamp = timestamp.as_i64();
+            query = match sort {
+                Sorting::Newest => query.filter(
+                    bso::modified
+                        .lt(timestamp)
+                        .or(bso::modified.eq(timestamp).and(bso::id.lt(id.to_owned()))),
+                ),
+                Sorting::Oldest => query.filter(
+                    bso::modified
+                        .gt(timestamp)
+                        .or(bso::modified.eq(timestamp).and(bso::id.gt(id.to_owned()))),
+                ),
+                _ => query,
+            };
+        }
+
         query = match sort {
             Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order(bso::modified.desc()),
-            Sorting::Oldest => query.order(bso::modified.asc()),
+            // The id breaks ties for stable keyset pagination
+            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
             _ => query,
         };
 
@@ -577,13 +636,17 @@ impl MysqlDb {
         // match the query conditions
         query = query.limit(if limit >= 0 { limit + 1 } else { limit });
 
-        let numeric_offset = offset.map_or(0, |offset| offset.offset as i64);
+        let numeric_offset = if keyset.is_some() {
+            0
+        } else {
+            offset.as_ref().map_or(0, |offset| offset.offset as i64)
+        };
         if numeric_offset != 0 {
             // XXX: copy over this optimization:
             // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
             query = query.offset(numeric_offset);
         }
-        let mut ids = query.load::<String>(&self.conn)?;
+        let mut ids = query.load::<(String, i64)>(&self.conn)?;
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
@@ -592,13 +655,17 @@ impl MysqlDb {
 
         let next_offset = if limit >= 0 && ids.len() > limit as usize {
             ids.pop();
-            Some((limit + numeric_offset).to_string())
+            let last = match ids.last() {
+                Some((id, modified)) => Some((SyncTimestamp::from_i64(*modified)?, id.as_str())),
+                None => None,
+            };
+            Some(encode_next_offset(sort, limit + numeric_offset, last))
         } else {
             None
         };
 
         Ok(results::GetBsoIds {
-            items: ids,
+            items: ids.into_iter().map(|(id, _)| id).collect(),
             offset: next_offset,
         })
     }
@@ -756,6 +823,22 @@ impl MysqlDb {
         Ok(result as u64 > 0)
     }
 
+    fn schema_version_sync(&self) -> Result<results::SchemaVersion> {
+        let applied = sql_query(
+            "SELECT version
+               FROM __diesel_schema_migrations
+              ORDER BY version DESC
+              LIMIT 1",
+        )
+        .load::<VersionResult>(&self.conn)?
+        .pop()
+        .map(|result| result.version);
+        Ok(results::SchemaVersion {
+            applied,
+            latest: LATEST_MIGRATION.to_owned(),
+        })
+    }
+
     fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> Result<HashMap<String, T>> {
         let mut names = self.load_collection_names(by_id.keys())?;
         by_id
@@ -877,6 +960,7 @@ impl MysqlDb {
     batch_db_method!(validate_batch_sync, validate, ValidateBatch);
     batch_db_method!(append_to_batch_sync, append, AppendToBatch);
     batch_db_method!(commit_batch_sync, commit, CommitBatch);
+    batch_db_method!(get_batch_usage_sync, get_usage, GetBatchUsage);
     #[cfg(test)]
     batch_db_method!(delete_batch_sync, delete, DeleteBatch);
 
@@ -887,6 +971,28 @@ impl MysqlDb {
     pub fn timestamp(&self) -> SyncTimestamp {
         self.session.borrow().timestamp
     }
+
+    /// Start the span of a db call
+    fn span(&self, name: &str) -> Span {
+        let mut span = Span::start(
+            name,
+            SpanKind::Client,
+            self.session.borrow().span_context.as_ref(),
+        );
+        span.set_attribute("db.system", "mysql");
+        span
+    }
+}
+
+/// Encode the offset of the next page: a keyset position after the last item
+/// returned for timestamp ordering, otherwise a numeric offset
+fn encode_next_offset(sort: Sorting, offset: i64, last: Option<(SyncTimestamp, &str)>) -> String {
+    match (sort, last) {
+        (Sorting::Newest, Some((modified, id))) | (Sorting::Oldest, Some((modified, id))) => {
+            Offset::after(modified, id).to_string()
+        }
+        _ => offset.to_string(),
+    }
 }
 
 macro_rules! sync_db_method {
@@ -896,7 +1002,16 @@ macro_rules! sync_db_method {
     ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
         fn $name(&self, params: params::$type) -> DbFuture<'_, $result> {
             let db = self.clone();
-            Box::pin(block(move || db.$sync_name(params).map_err(Into::into)).map_err(Into::into))
+            Box::pin(
+                block(move || {
+                    let mut metrics = db.metrics.clone();
+                    metrics.start_db_timer(concat!("storage.sql.", stringify!($name)));
+                    let _span = db.span(concat!("mysql.", stringify!($name)));
+                    let query = db.slow_query.start(stringify!($name), &params);
+                    query.finish(db.$sync_name(params)).map_err(Into::into)
+                })
+                .map_err(Into::into),
+            )
         }
     };
 }
@@ -904,7 +1019,13 @@ macro_rules! sync_db_method {
 impl<'a> Db<'a> for MysqlDb {
     fn commit(&self) -> DbFuture<'_, ()> {
         let db = self.clone();
-        Box::pin(block(move || db.commit_sync().map_err(Into::into)).map_err(Into::into))
+        Box::pin(
+            block(move || {
+                let _span = db.span("mysql.commit");
+                db.commit_sync().map_err(Into::into)
+            })
+            .map_err(Into::into),
+        )
     }
 
     fn rollback(&self) -> DbFuture<'_, ()> {
@@ -926,6 +1047,18 @@ impl<'a> Db<'a> for MysqlDb {
         Box::pin(block(move || db.check_sync().map_err(Into::into)).map_err(Into::into))
     }
 
+    fn schema_version(&self) -> DbFuture<'_, Option<results::SchemaVersion>> {
+        let db = self.clone();
+        Box::pin(
+            block(move || db.schema_version_sync().map(Some).map_err(Into::into))
+                .map_err(Into::into),
+        )
+    }
+
+    fn set_span_context(&self, context: Option<SpanContext>) {
+        self.session.borrow_mut().span_context = context;
+    }
+
     sync_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
     sync_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
     sync_db_method!(
@@ -978,6 +1111,7 @@ impl<'a> Db<'a> for MysqlDb {
         GetBatch,
         Option<results::GetBatch>
     );
+    sync_db_method!(get_batch_usage, get_batch_usage_sync, GetBatchUsage);
     sync_db_method!(commit_batch, commit_batch_sync, CommitBatch);
 
     #[cfg(test)]
@@ -1039,6 +1173,12 @@ struct NameResult {
     name: String,
 }
 
+#[derive(Debug, QueryableByName)]
+struct VersionResult {
+    #[sql_type = "Text"]
+    version: String,
+}
+
 #[derive(Debug, QueryableByName)]
 struct UserCollectionsResult {
     // Can't substitute column names here.
@@ -17,7 +17,9 @@ use diesel::{
 use super::models::{MysqlDb, Result};
 #[cfg(test)]
 use super::test::TestTransactionCustomizer;
-use crate::db::{error::DbError, results, Db, DbPool, STD_COLLS};
+use crate::db::{
+    error::DbError, results, slow_query::SlowQueryLog, Db, DbPool, PoolTimeouts, STD_COLLS,
+};
 use crate::error::{ApiError, ApiResult};
 use crate::server::metrics::Metrics;
 use crate::settings::Settings;
@@ -40,6 +42,8 @@ pub struct MysqlDbPool {
     /// Thread Pool for running synchronous db calls
     /// In-memory cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// Logging of slow db calls
+    slow_query: SlowQueryLog,
 
     metrics: Metrics,
 }
@@ -55,9 +59,14 @@ impl MysqlDbPool {
 
     pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
         let manager = ConnectionManager::<MysqlConnection>::new(settings.database_url.clone());
+        let timeouts = PoolTimeouts::new(settings);
         let builder = Pool::builder()
             .max_size(settings.database_pool_max_size.unwrap_or(10))
-            .min_idle(settings.database_pool_min_idle);
+            .min_idle(settings.database_pool_min_idle)
+            .connection_timeout(timeouts.connection_timeout)
+            .idle_timeout(timeouts.idle_timeout)
+            .max_lifetime(timeouts.max_lifetime)
+            .test_on_check_out(timeouts.test_on_checkout);
 
         #[cfg(test)]
         let builder = if settings.database_use_test_transactions {
@@ -69,15 +78,27 @@ impl MysqlDbPool {
         Ok(Self {
             pool: builder.build(manager)?,
             coll_cache: Default::default(),
+            slow_query: SlowQueryLog::new(&settings.slow_query, metrics),
             metrics: metrics.clone(),
         })
     }
 
+    /// Check out a connection, timing the wait in the "storage.pool.get"
+    /// metric and counting timeouts in "storage.pool.timeout"
     pub fn get_sync(&self) -> Result<MysqlDb> {
+        let mut timer = self.metrics.clone();
+        timer.start_timer("storage.pool.get", None);
+        // r2d2 only fails a checkout once it's timed out
+        let conn = self.pool.get().map_err(|e| {
+            self.metrics.incr("storage.pool.timeout");
+            e
+        })?;
+        drop(timer);
         Ok(MysqlDb::new(
-            self.pool.get()?,
+            conn,
             Arc::clone(&self.coll_cache),
             &self.metrics,
+            &self.slow_query,
         ))
     }
 }
@@ -92,7 +113,10 @@ impl DbPool for MysqlDbPool {
     }
 
     fn state(&self) -> results::PoolState {
-        self.pool.state().into()
+        results::PoolState {
+            max_size: self.pool.max_size(),
+            ..self.pool.state().into()
+        }
     }
 
     fn validate_batch_id(&self, id: String) -> Result<()> {
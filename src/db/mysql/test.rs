@@ -9,7 +9,7 @@ use diesel::{
 use url::Url;
 
 use crate::db::mysql::{
-    models::{MysqlDb, Result},
+    models::{MysqlDb, Result, LATEST_MIGRATION},
     pool::MysqlDbPool,
     schema::collections,
 };
@@ -97,3 +97,24 @@ fn static_collection_id() -> Result<()> {
     assert!(cid >= 100);
     Ok(())
 }
+
+#[test]
+fn latest_migration() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let latest = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        // Diesel's version of a migration is the digits of its name's prefix
+        .filter_map(|name| {
+            let prefix = name.split('_').next()?;
+            Some(
+                prefix
+                    .chars()
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>(),
+            )
+        })
+        .max()
+        .unwrap();
+    assert_eq!(latest, LATEST_MIGRATION);
+}
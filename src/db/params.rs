@@ -3,6 +3,7 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::db::slow_query::QueryParams;
 use crate::web::extractors::{BatchBsoBody, BsoQueryParams, HawkIdentifier};
 
 macro_rules! data {
@@ -29,6 +30,12 @@ macro_rules! collection_data {
                 $($property: $type,)*
             }
         }
+
+        impl QueryParams for $name {
+            fn collection(&self) -> Option<&str> {
+                Some(&self.collection)
+            }
+        }
     )+)
 }
 
@@ -42,6 +49,12 @@ macro_rules! bso_data {
                 $($property: $type,)*
             }
         }
+
+        impl QueryParams for $name {
+            fn collection(&self) -> Option<&str> {
+                Some(&self.collection)
+            }
+        }
     )+)
 }
 
@@ -85,6 +98,11 @@ collection_data! {
     GetBatch {
         id: String,
     },
+    GetBatchUsage {
+        id: String,
+        excluding: Vec<String>,
+        excluding_payloads: Vec<String>,
+    },
     DeleteBatch {
         id: String,
     },
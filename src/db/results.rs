@@ -30,6 +30,20 @@ pub type CommitBatch = PostBsos;
 pub type ValidateBatchId = ();
 pub type Check = bool;
 
+/// The applied and latest versions of a db schema managed by migrations
+#[derive(Debug, Default)]
+pub struct SchemaVersion {
+    pub applied: Option<String>,
+    pub latest: String,
+}
+
+/// The pending items of a batch, counted against the max_total_* limits
+#[derive(Debug, Default)]
+pub struct GetBatchUsage {
+    pub count: u64,
+    pub total_bytes: u64,
+}
+
 #[derive(Debug, Default, Deserialize, Queryable, QueryableByName, Serialize)]
 pub struct GetBso {
     #[sql_type = "Text"]
@@ -74,6 +88,19 @@ pub struct PostBsos {
 pub struct PoolState {
     pub connections: u32,
     pub idle_connections: u32,
+    /// The pool's maximum number of connections (0 when unknown)
+    pub max_size: u32,
+}
+
+impl PoolState {
+    /// The percentage of the pool's maximum connections in use
+    pub fn saturation(&self) -> Option<u32> {
+        if self.max_size == 0 {
+            return None;
+        }
+        let active = self.connections.saturating_sub(self.idle_connections);
+        Some(active * 100 / self.max_size)
+    }
 }
 
 impl From<diesel::r2d2::State> for PoolState {
@@ -81,6 +108,7 @@ impl From<diesel::r2d2::State> for PoolState {
         PoolState {
             connections: state.connections,
             idle_connections: state.idle_connections,
+            max_size: 0,
         }
     }
 }
@@ -90,6 +118,7 @@ impl From<bb8::State> for PoolState {
         PoolState {
             connections: state.connections,
             idle_connections: state.idle_connections,
+            max_size: 0,
         }
     }
 }
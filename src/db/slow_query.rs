@@ -0,0 +1,175 @@
+//! Detection and logging of slow db calls.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::db::{params, results, util::SyncTimestamp};
+use crate::server::metrics::Metrics;
+use crate::settings::SlowQuery;
+use crate::web::{extractors::HawkIdentifier, tags::Tags};
+
+/// The collection of a db call's params, if any
+pub trait QueryParams {
+    fn collection(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The number of rows of a db call's result, when meaningful
+pub trait QueryResult {
+    fn row_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Logs db calls taking longer than `SlowQuery::threshold_ms`, counting them
+/// in the "storage.slow_query" metric (tagged by operation).
+#[derive(Clone, Debug)]
+pub struct SlowQueryLog {
+    /// None when disabled
+    threshold: Option<Duration>,
+    query_stats: bool,
+    metrics: Metrics,
+}
+
+impl SlowQueryLog {
+    pub fn new(settings: &SlowQuery, metrics: &Metrics) -> Self {
+        Self {
+            threshold: if settings.threshold_ms > 0 {
+                Some(Duration::from_millis(settings.threshold_ms.into()))
+            } else {
+                None
+            },
+            query_stats: settings.spanner_query_stats,
+            metrics: metrics.clone(),
+        }
+    }
+
+    /// Whether a call taking `elapsed` is slow
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        self.threshold
+            .map_or(false, |threshold| elapsed > threshold)
+    }
+
+    /// Whether Spanner statements are profiled, their query stats and plans
+    /// logged when slow
+    pub fn query_stats(&self) -> bool {
+        self.threshold.is_some() && self.query_stats
+    }
+
+    /// Begin timing a call of `operation` with `params`
+    pub fn start<P: QueryParams>(&self, operation: &'static str, params: &P) -> QueryTimer<'_> {
+        QueryTimer {
+            log: self,
+            operation,
+            collection: self
+                .threshold
+                .and_then(|_| params.collection())
+                .map(ToOwned::to_owned),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// A timed db call
+pub struct QueryTimer<'a> {
+    log: &'a SlowQueryLog,
+    operation: &'static str,
+    collection: Option<String>,
+    start: Instant,
+}
+
+impl QueryTimer<'_> {
+    /// Log the call if it was slow, passing through its `result`
+    pub fn finish<R: QueryResult, E>(self, result: Result<R, E>) -> Result<R, E> {
+        let elapsed = self.start.elapsed();
+        if self.log.is_slow(elapsed) {
+            let mut tags = HashMap::new();
+            tags.insert("operation".to_owned(), self.operation.to_owned());
+            self.log
+                .metrics
+                .incr_with_tags("storage.slow_query", Some(Tags::with_tags(tags)));
+            warn!(
+                "🐢 Slow query: {}", self.operation;
+                "operation" => self.operation,
+                "collection" => self.collection.as_deref(),
+                "rows" => result.as_ref().ok().and_then(QueryResult::row_count),
+                "error" => result.is_err(),
+                "elapsed_ms" => elapsed.as_millis() as u64,
+            );
+        }
+        result
+    }
+}
+
+impl QueryParams for HawkIdentifier {}
+impl QueryParams for String {}
+
+impl QueryParams for params::PutBso {
+    fn collection(&self) -> Option<&str> {
+        Some(&self.collection)
+    }
+}
+
+impl QueryResult for () {}
+impl QueryResult for bool {}
+impl QueryResult for u64 {}
+impl QueryResult for String {}
+impl QueryResult for SyncTimestamp {}
+impl QueryResult for results::GetBatchUsage {}
+
+impl<V> QueryResult for HashMap<String, V> {
+    fn row_count(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<T> QueryResult for Option<T> {
+    fn row_count(&self) -> Option<usize> {
+        Some(if self.is_some() { 1 } else { 0 })
+    }
+}
+
+impl<T: serde::Serialize> QueryResult for results::Paginated<T> {
+    fn row_count(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+impl QueryResult for results::PostBsos {
+    fn row_count(&self) -> Option<usize> {
+        Some(self.success.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SlowQueryLog;
+    use crate::server::metrics::Metrics;
+    use crate::settings::SlowQuery;
+
+    #[test]
+    fn test_is_slow() {
+        let log = SlowQueryLog::new(
+            &SlowQuery {
+                threshold_ms: 100,
+                spanner_query_stats: true,
+            },
+            &Metrics::noop(),
+        );
+        assert!(!log.is_slow(Duration::from_millis(100)));
+        assert!(log.is_slow(Duration::from_millis(101)));
+        assert!(log.query_stats());
+
+        let log = SlowQueryLog::new(
+            &SlowQuery {
+                threshold_ms: 0,
+                spanner_query_stats: true,
+            },
+            &Metrics::noop(),
+        );
+        assert!(!log.is_slow(Duration::from_secs(60)));
+        assert!(!log.query_stats());
+    }
+}
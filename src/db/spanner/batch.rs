@@ -7,7 +7,7 @@ use protobuf::{
 };
 use uuid::Uuid;
 
-use super::support::{null_value, struct_type_field};
+use super::support::{as_list_value, null_value, struct_type_field};
 use super::{
     models::{Result, SpannerDb, DEFAULT_BSO_TTL, PRETOUCH_TS},
     support::as_value,
@@ -142,6 +142,52 @@ pub async fn get_async(
     Ok(batch)
 }
 
+pub async fn get_usage_async(
+    db: &SpannerDb<'_>,
+    params: params::GetBatchUsage,
+) -> Result<results::GetBatchUsage> {
+    let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let mut sqlparams = params! {
+        "fxa_uid" => params.user_id.fxa_uid,
+        "fxa_kid" => params.user_id.fxa_kid,
+        "collection_id" => collection_id.to_string(),
+        "batch_id" => params.id,
+    };
+    sqlparams.insert(
+        "excluding".to_owned(),
+        as_list_value(params.excluding.into_iter()),
+    );
+    sqlparams.insert(
+        "excluding_payloads".to_owned(),
+        as_list_value(params.excluding_payloads.into_iter()),
+    );
+    let result = db
+        .sql(
+            "SELECT COUNTIF(batch_bso_id NOT IN UNNEST(@excluding)),
+                    COALESCE(SUM(IF(batch_bso_id IN UNNEST(@excluding_payloads),
+                                    0,
+                                    BYTE_LENGTH(payload))), 0)
+               FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id",
+        )?
+        .params(sqlparams)
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    let count = result[0]
+        .get_string_value()
+        .parse::<u64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+    let total_bytes = result[1]
+        .get_string_value()
+        .parse::<u64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?;
+    Ok(results::GetBatchUsage { count, total_bytes })
+}
+
 pub async fn delete_async(db: &SpannerDb<'_>, params: params::DeleteBatch) -> Result<()> {
     let collection_id = db.get_collection_id_async(&params.collection).await?;
     // Also deletes child batch_bsos rows (INTERLEAVE IN PARENT batches ON
@@ -4,7 +4,7 @@ use std::{fmt, sync::Arc};
 use async_trait::async_trait;
 use bb8::ManageConnection;
 use googleapis_raw::spanner::v1::{
-    spanner::{CreateSessionRequest, GetSessionRequest, Session},
+    spanner::{CreateSessionRequest, DeleteSessionRequest, GetSessionRequest, Session},
     spanner_grpc::SpannerClient,
 };
 use grpcio::{
@@ -130,3 +130,11 @@ async fn create_session(
     let opt = CallOption::default().headers(meta.build());
     client.create_session_async_opt(&req, opt)?.await
 }
+
+/// Delete a connection's session, rather than leaving it to expire
+pub async fn delete_session(conn: &SpannerSession) -> Result<(), grpcio::Error> {
+    let mut req = DeleteSessionRequest::new();
+    req.set_name(conn.session.get_name().to_owned());
+    conn.client.delete_session_async(&req)?.await?;
+    Ok(())
+}
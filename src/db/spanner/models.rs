@@ -15,11 +15,13 @@ use super::pool::CollectionCache;
 use crate::db::{
     error::{DbError, DbErrorKind},
     params, results,
+    slow_query::SlowQueryLog,
     spanner::support::{as_type, StreamedResultSetAsync},
     util::SyncTimestamp,
     Db, DbFuture, Sorting, FIRST_CUSTOM_COLLECTION_ID,
 };
 use crate::server::metrics::Metrics;
+use crate::tracing::{Span, SpanContext, SpanKind};
 
 use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
 
@@ -81,6 +83,8 @@ struct SpannerDbSession {
     execute_sql_count: u64,
     /// Whether touch_collection has already been called
     touched_collection: bool,
+    /// Parent of the spans of this session's calls
+    span_context: Option<SpanContext>,
 }
 
 #[derive(Clone, Debug)]
@@ -91,6 +95,8 @@ pub struct SpannerDb<'a> {
     coll_cache: Arc<CollectionCache>,
 
     pub metrics: Metrics,
+
+    slow_query: SlowQueryLog,
 }
 
 pub struct SpannerDbInner<'a> {
@@ -114,7 +120,12 @@ impl<'a> Deref for SpannerDb<'a> {
 }
 
 impl<'a> SpannerDb<'a> {
-    pub fn new(conn: Conn<'a>, coll_cache: Arc<CollectionCache>, metrics: &Metrics) -> Self {
+    pub fn new(
+        conn: Conn<'a>,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        slow_query: &SlowQueryLog,
+    ) -> Self {
         let inner = SpannerDbInner {
             conn,
             session: RefCell::new(Default::default()),
@@ -123,6 +134,7 @@ impl<'a> SpannerDb<'a> {
             inner: Arc::new(inner),
             coll_cache,
             metrics: metrics.clone(),
+            slow_query: slow_query.clone(),
         }
     }
 
@@ -232,8 +244,12 @@ impl<'a> SpannerDb<'a> {
     }
 
     pub async fn lock_for_write_async(&self, params: params::LockCollection) -> Result<()> {
-        // Begin a transaction
-        self.begin_async(true).await?;
+        // Begin a transaction (reusing it when write locking multiple
+        // collections, which then all share its timestamp)
+        let in_write_transaction = self.in_write_transaction();
+        if !in_write_transaction {
+            self.begin_async(true).await?;
+        }
         let collection_id = self
             .get_or_create_collection_id_async(&params.collection)
             .await?;
@@ -271,7 +287,11 @@ impl<'a> SpannerDb<'a> {
 
         let timestamp = if let Some(result) = result {
             let modified = SyncTimestamp::from_rfc3339(result[1].get_string_value())?;
-            let now = SyncTimestamp::from_rfc3339(result[0].get_string_value())?;
+            let now = if in_write_transaction {
+                self.timestamp()?
+            } else {
+                SyncTimestamp::from_rfc3339(result[0].get_string_value())?
+            };
             // Forbid the write if it would not properly incr the modified
             // timestamp
             if modified >= now {
@@ -283,6 +303,8 @@ impl<'a> SpannerDb<'a> {
                 .coll_modified_cache
                 .insert((params.user_id.clone(), collection_id), modified);
             now
+        } else if in_write_transaction {
+            self.timestamp()?
         } else {
             let result = self
                 .sql("SELECT CURRENT_TIMESTAMP()")?
@@ -381,7 +403,24 @@ impl<'a> SpannerDb<'a> {
     }
 
     pub(super) fn sql(&self, sql: &str) -> Result<ExecuteSqlRequestBuilder> {
-        Ok(ExecuteSqlRequestBuilder::new(self.sql_request(sql)?))
+        let mut span = self.span("spanner.execute_sql");
+        span.set_attribute("db.statement", sql);
+        let builder = ExecuteSqlRequestBuilder::new(self.sql_request(sql)?).span(span);
+        if self.slow_query.query_stats() {
+            return Ok(builder.profile(self.slow_query.clone()));
+        }
+        Ok(builder)
+    }
+
+    /// Start the span of a db call
+    fn span(&self, name: &str) -> Span {
+        let mut span = Span::start(
+            name,
+            SpanKind::Client,
+            self.session.borrow().span_context.as_ref(),
+        );
+        span.set_attribute("db.system", "spanner");
+        span
     }
 
     pub(super) fn insert(&self, table: &str, columns: &[&str], values: Vec<ListValue>) {
@@ -481,6 +520,7 @@ impl<'a> SpannerDb<'a> {
             if let Some(mutations) = self.session.borrow_mut().mutations.take() {
                 req.set_mutations(RepeatedField::from_vec(mutations));
             }
+            let _span = self.span("spanner.commit");
             spanner.client.commit_async(&req)?.await?;
             Ok(())
         } else {
@@ -1034,24 +1074,28 @@ impl<'a> SpannerDb<'a> {
             sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
         }
 
-        // issue559: Dead code (timestamp always None)
-        /*
-        if let Some(timestamp) = offset.clone().unwrap_or_default().timestamp {
-            query = match sort {
-                Sorting::Newest => {
-                    sqlparams.insert("older_eq".to_string(), as_value(timestamp.as_rfc3339()?));
-                    sqltypes.insert("older_eq".to_string(), as_type(TypeCode::TIMESTAMP));
-                    format!("{} AND modified <= @older_eq", query)
-                }
-                Sorting::Oldest => {
-                    sqlparams.insert("newer_eq".to_string(), as_value(timestamp.as_rfc3339()?));
-                    sqltypes.insert("newer_eq".to_string(), as_type(TypeCode::TIMESTAMP));
-                    format!("{} AND modified >= @newer_eq", query)
-                }
-                _ => query,
+        // Resume immediately after the previous page's last item
+        let keyset = offset
+            .as_ref()
+            .and_then(Offset::keyset)
+            .map(|(timestamp, id)| (timestamp, id.to_owned()));
+        if let Some((timestamp, id)) = keyset.as_ref() {
+            let condition = match sort {
+                Sorting::Newest => Some(
+                    "(modified < @offset_ts OR (modified = @offset_ts AND bso_id < @offset_id))",
+                ),
+                Sorting::Oldest => Some(
+                    "(modified > @offset_ts OR (modified = @offset_ts AND bso_id > @offset_id))",
+                ),
+                _ => None,
             };
+            if let Some(condition) = condition {
+                query = format!("{} AND {}", query, condition);
+                sqlparams.insert("offset_ts".to_string(), as_value(timestamp.as_rfc3339()?));
+                sqltypes.insert("offset_ts".to_string(), as_type(TypeCode::TIMESTAMP));
+                sqlparams.insert("offset_id".to_string(), as_value(id.clone()));
+            }
         }
-        */
         if let Some(older) = older {
             query = format!("{} AND modified < @older", query);
             sqlparams.insert("older".to_string(), as_value(older.as_rfc3339()?));
@@ -1063,19 +1107,14 @@ impl<'a> SpannerDb<'a> {
             sqltypes.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
         }
         query = match sort {
-            // issue559: Revert to previous sorting
-            /*
-            Sorting::Index => format!("{} ORDER BY sortindex DESC, bso_id DESC", query),
-            Sorting::Newest | Sorting::None => {
-                format!("{} ORDER BY modified DESC, bso_id DESC", query)
-            }
-            Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
-            */
             Sorting::Index => format!("{} ORDER BY sortindex DESC", query),
-            Sorting::Newest => format!("{} ORDER BY modified DESC", query),
-            Sorting::Oldest => format!("{} ORDER BY modified ASC", query),
+            // bso_id breaks ties for stable keyset pagination
+            Sorting::Newest => format!("{} ORDER BY modified DESC, bso_id DESC", query),
+            Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
             _ => query,
         };
+        // Keyset positions replace the numeric offset
+        let offset = offset.filter(|_| keyset.is_none());
 
         if let Some(limit) = limit {
             // fetch an extra row to detect if there are more rows that match
@@ -1103,57 +1142,25 @@ impl<'a> SpannerDb<'a> {
             .execute_async(&self.conn)
     }
 
+    /// Encode the offset of the next page: a keyset position after the last
+    /// item returned for timestamp ordering, otherwise a numeric offset
     pub fn encode_next_offset(
         &self,
-        _sort: Sorting,
+        sort: Sorting,
         offset: u64,
-        _timestamp: Option<i64>,
-        modifieds: Vec<i64>,
+        last: Option<(SyncTimestamp, &str)>,
+        count: usize,
     ) -> Option<String> {
-        // issue559: Use a simple numeric offset everwhere as previously for
-        // now: was previously a value of "limit + offset", modifieds.len()
-        // always equals limit
-        Some(
-            Offset {
-                offset: offset + modifieds.len() as u64,
-                timestamp: None,
+        Some(match (sort, last) {
+            (Sorting::Newest, Some((modified, id))) | (Sorting::Oldest, Some((modified, id))) => {
+                Offset::after(modified, id).to_string()
             }
-            .to_string(),
-        )
-        /*
-        let mut calc_offset = 1;
-        let mut i = (modifieds.len() as i64) - 2;
-
-        let prev_bound = match sort {
-            Sorting::Index => {
-                // Use a simple numeric offset for sortindex ordering.
-                return Some(
-                    Offset {
-                        offset: offset + modifieds.len() as u64,
-                        timestamp: None,
-                    }
-                    .to_string(),
-                );
+            _ => Offset {
+                offset: offset + count as u64,
+                ..Default::default()
             }
-            Sorting::None => timestamp,
-            Sorting::Newest => timestamp,
-            Sorting::Oldest => timestamp,
-        };
-        // Find an appropriate upper bound for faster timestamp ordering.
-        let bound = *modifieds.last().unwrap_or(&0);
-        // Count how many previous items have that same timestamp, and hence
-        // will need to be skipped over.  The number of matches here is limited
-        // by upload batch size.
-        while i >= 0 && modifieds[i as usize] == bound {
-            calc_offset += 1;
-            i -= 1;
-        }
-        if i < 0 && prev_bound.is_some() && prev_bound.unwrap() == bound {
-            calc_offset += offset;
-        }
-
-        Some(format!("{}:{}", bound, calc_offset))
-        */
+            .to_string(),
+        })
     }
 
     pub async fn get_bsos_async(&self, params: params::GetBsos) -> Result<results::GetBsos> {
@@ -1165,7 +1172,11 @@ impl<'a> SpannerDb<'a> {
                AND collection_id = @collection_id
                AND expiry > CURRENT_TIMESTAMP()";
         let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
+        let offset = params
+            .params
+            .offset
+            .as_ref()
+            .map_or(0, |offset| offset.offset);
         let sort = params.params.sort;
 
         let mut streaming = self.bsos_query_async(query, params).await?;
@@ -1184,8 +1195,8 @@ impl<'a> SpannerDb<'a> {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            let last = bsos.last().map(|bso| (bso.modified, bso.id.as_str()));
+            self.encode_next_offset(sort, offset, last, bsos.len())
         } else {
             None
         };
@@ -1198,7 +1209,11 @@ impl<'a> SpannerDb<'a> {
 
     pub async fn get_bso_ids_async(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
         let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
+        let offset = params
+            .params
+            .offset
+            .as_ref()
+            .map_or(0, |offset| offset.offset);
         let sort = params.params.sort;
 
         let query = "\
@@ -1215,7 +1230,7 @@ impl<'a> SpannerDb<'a> {
         while let Some(row) = stream.next_async().await {
             let mut row = row?;
             ids.push(row[0].take_string_value());
-            modifieds.push(SyncTimestamp::from_rfc3339(row[1].get_string_value())?.as_i64());
+            modifieds.push(SyncTimestamp::from_rfc3339(row[1].get_string_value())?);
         }
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
         // makes an additional call to get_collection_timestamp to potentially
@@ -1227,7 +1242,11 @@ impl<'a> SpannerDb<'a> {
         let next_offset = if limit >= 0 && ids.len() > limit as usize {
             ids.pop();
             modifieds.pop();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            let last = match (modifieds.last(), ids.last()) {
+                (Some(modified), Some(id)) => Some((*modified, id.as_str())),
+                _ => None,
+            };
+            self.encode_next_offset(sort, offset, last, ids.len())
         } else {
             None
         };
@@ -1609,6 +1628,23 @@ impl<'a> SpannerDb<'a> {
     }
 }
 
+macro_rules! spanner_db_method {
+    ($name:ident, $async_name:path, $type:ident) => {
+        spanner_db_method!($name, $async_name, $type, results::$type);
+    };
+    ($name:ident, $async_name:path, $type:ident, $result:ty) => {
+        fn $name(&self, param: params::$type) -> DbFuture<'_, $result> {
+            let db = self.clone();
+            Box::pin(async move {
+                let mut metrics = db.metrics.clone();
+                metrics.start_db_timer(concat!("storage.spanner.", stringify!($name)));
+                let query = db.slow_query.start(stringify!($name), &param);
+                query.finish($async_name(&db, param).map_err(Into::into).await)
+            })
+        }
+    };
+}
+
 impl<'a> Db<'a> for SpannerDb<'a> {
     fn commit(&self) -> DbFuture<'_, ()> {
         let db = self.clone();
@@ -1620,48 +1656,29 @@ impl<'a> Db<'a> for SpannerDb<'a> {
         Box::pin(async move { db.rollback_async().map_err(Into::into).await })
     }
 
-    fn lock_for_read(&self, param: params::LockCollection) -> DbFuture<'_, ()> {
-        let db = self.clone();
-        Box::pin(async move { db.lock_for_read_async(param).map_err(Into::into).await })
-    }
-
-    fn lock_for_write(&self, param: params::LockCollection) -> DbFuture<'_, ()> {
-        let db = self.clone();
-        Box::pin(async move { db.lock_for_write_async(param).map_err(Into::into).await })
-    }
+    spanner_db_method!(lock_for_read, Self::lock_for_read_async, LockCollection);
+    spanner_db_method!(lock_for_write, Self::lock_for_write_async, LockCollection);
 
     fn begin(&self, for_write: bool) -> DbFuture<'_, ()> {
         let db = self.clone();
         Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
     }
 
-    fn get_collection_timestamp(
-        &self,
-        param: params::GetCollectionTimestamp,
-    ) -> DbFuture<'_, results::GetCollectionTimestamp> {
-        let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_timestamp_async(param)
-                .map_err(Into::into)
-                .await
-        })
-    }
-
-    fn get_storage_timestamp(
-        &self,
-        param: params::GetStorageTimestamp,
-    ) -> DbFuture<'_, results::GetStorageTimestamp> {
-        let db = self.clone();
-        Box::pin(async move { db.get_storage_timestamp(param).map_err(Into::into).await })
-    }
-
-    fn delete_collection(
-        &self,
-        param: params::DeleteCollection,
-    ) -> DbFuture<'_, results::DeleteCollection> {
-        let db = self.clone();
-        Box::pin(async move { db.delete_collection_async(param).map_err(Into::into).await })
-    }
+    spanner_db_method!(
+        get_collection_timestamp,
+        Self::get_collection_timestamp_async,
+        GetCollectionTimestamp
+    );
+    spanner_db_method!(
+        get_storage_timestamp,
+        Self::get_storage_timestamp,
+        GetStorageTimestamp
+    );
+    spanner_db_method!(
+        delete_collection,
+        Self::delete_collection_async,
+        DeleteCollection
+    );
 
     fn box_clone(&self) -> Box<dyn Db<'a>> {
         Box::new(self.clone())
@@ -1672,139 +1689,69 @@ impl<'a> Db<'a> for SpannerDb<'a> {
         Box::pin(async move { db.check_async().map_err(Into::into).await })
     }
 
-    fn get_collection_timestamps(
-        &self,
-        user_id: params::GetCollectionTimestamps,
-    ) -> DbFuture<'_, results::GetCollectionTimestamps> {
-        let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_timestamps_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
-    }
-
-    fn get_collection_counts(
-        &self,
-        user_id: params::GetCollectionCounts,
-    ) -> DbFuture<'_, results::GetCollectionCounts> {
-        let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_counts_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
-    }
-
-    fn get_collection_usage(
-        &self,
-        user_id: params::GetCollectionUsage,
-    ) -> DbFuture<'_, results::GetCollectionUsage> {
-        let db = self.clone();
-        Box::pin(async move {
-            db.get_collection_usage_async(user_id)
-                .map_err(Into::into)
-                .await
-        })
-    }
-
-    fn get_storage_usage(
-        &self,
-        param: params::GetStorageUsage,
-    ) -> DbFuture<'_, results::GetStorageUsage> {
-        let db = self.clone();
-        Box::pin(async move { db.get_storage_usage_async(param).map_err(Into::into).await })
-    }
-
-    fn delete_storage(&self, param: params::DeleteStorage) -> DbFuture<'_, results::DeleteStorage> {
-        let db = self.clone();
-        Box::pin(async move { db.delete_storage_async(param).map_err(Into::into).await })
-    }
-
-    fn delete_bso(&self, param: params::DeleteBso) -> DbFuture<'_, results::DeleteBso> {
-        let db = self.clone();
-        Box::pin(async move { db.delete_bso_async(param).map_err(Into::into).await })
-    }
-
-    fn delete_bsos(&self, param: params::DeleteBsos) -> DbFuture<'_, results::DeleteBsos> {
-        let db = self.clone();
-        Box::pin(async move { db.delete_bsos_async(param).map_err(Into::into).await })
-    }
-
-    fn get_bsos(&self, param: params::GetBsos) -> DbFuture<'_, results::GetBsos> {
-        let db = self.clone();
-        Box::pin(async move { db.get_bsos_async(param).map_err(Into::into).await })
-    }
-
-    fn get_bso_ids(&self, param: params::GetBsoIds) -> DbFuture<'_, results::GetBsoIds> {
-        let db = self.clone();
-        Box::pin(async move { db.get_bso_ids_async(param).map_err(Into::into).await })
-    }
-
-    fn get_bso(&self, param: params::GetBso) -> DbFuture<'_, Option<results::GetBso>> {
-        let db = self.clone();
-        Box::pin(async move { db.get_bso_async(param).map_err(Into::into).await })
-    }
-
-    fn get_bso_timestamp(
-        &self,
-        param: params::GetBsoTimestamp,
-    ) -> DbFuture<'_, results::GetBsoTimestamp> {
-        let db = self.clone();
-        Box::pin(async move { db.get_bso_timestamp_async(param).map_err(Into::into).await })
-    }
+    fn set_span_context(&self, context: Option<SpanContext>) {
+        self.session.borrow_mut().span_context = context;
+    }
+
+    spanner_db_method!(
+        get_collection_timestamps,
+        Self::get_collection_timestamps_async,
+        GetCollectionTimestamps
+    );
+    spanner_db_method!(
+        get_collection_counts,
+        Self::get_collection_counts_async,
+        GetCollectionCounts
+    );
+    spanner_db_method!(
+        get_collection_usage,
+        Self::get_collection_usage_async,
+        GetCollectionUsage
+    );
+    spanner_db_method!(
+        get_storage_usage,
+        Self::get_storage_usage_async,
+        GetStorageUsage
+    );
+    spanner_db_method!(delete_storage, Self::delete_storage_async, DeleteStorage);
+    spanner_db_method!(delete_bso, Self::delete_bso_async, DeleteBso);
+    spanner_db_method!(delete_bsos, Self::delete_bsos_async, DeleteBsos);
+    spanner_db_method!(get_bsos, Self::get_bsos_async, GetBsos);
+    spanner_db_method!(get_bso_ids, Self::get_bso_ids_async, GetBsoIds);
+    spanner_db_method!(
+        get_bso,
+        Self::get_bso_async,
+        GetBso,
+        Option<results::GetBso>
+    );
+    spanner_db_method!(
+        get_bso_timestamp,
+        Self::get_bso_timestamp_async,
+        GetBsoTimestamp
+    );
 
     #[cfg(not(test))]
-    fn put_bso(&self, param: params::PutBso) -> DbFuture<'_, results::PutBso> {
-        let db = self.clone();
-        Box::pin(async move { db.put_bso_async(param).map_err(Into::into).await })
-    }
+    spanner_db_method!(put_bso, Self::put_bso_async, PutBso);
 
     #[cfg(test)]
-    fn put_bso(&self, param: params::PutBso) -> DbFuture<'_, results::PutBso> {
-        let db = self.clone();
-        Box::pin(async move { db.put_bso_async_test(param).map_err(Into::into).await })
-    }
+    spanner_db_method!(put_bso, Self::put_bso_async_test, PutBso);
 
     #[cfg(not(test))]
-    fn post_bsos(&self, param: params::PostBsos) -> DbFuture<'_, results::PostBsos> {
-        let db = self.clone();
-        Box::pin(async move { db.post_bsos_async(param).map_err(Into::into).await })
-    }
+    spanner_db_method!(post_bsos, Self::post_bsos_async, PostBsos);
 
     #[cfg(test)]
-    fn post_bsos(&self, param: params::PostBsos) -> DbFuture<'_, results::PostBsos> {
-        let db = self.clone();
-        Box::pin(async move { db.post_bsos_async_test(param).map_err(Into::into).await })
-    }
-
-    fn create_batch(&self, param: params::CreateBatch) -> DbFuture<'_, results::CreateBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::create_async(&db, param).map_err(Into::into).await })
-    }
-
-    fn validate_batch(&self, param: params::ValidateBatch) -> DbFuture<'_, results::ValidateBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::validate_async(&db, param).map_err(Into::into).await })
-    }
-
-    fn append_to_batch(
-        &self,
-        param: params::AppendToBatch,
-    ) -> DbFuture<'_, results::AppendToBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::append_async(&db, param).map_err(Into::into).await })
-    }
-
-    fn get_batch(&self, param: params::GetBatch) -> DbFuture<'_, Option<results::GetBatch>> {
-        let db = self.clone();
-        Box::pin(async move { batch::get_async(&db, param).map_err(Into::into).await })
-    }
-
-    fn commit_batch(&self, param: params::CommitBatch) -> DbFuture<'_, results::CommitBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::commit_async(&db, param).map_err(Into::into).await })
-    }
+    spanner_db_method!(post_bsos, Self::post_bsos_async_test, PostBsos);
+    spanner_db_method!(create_batch, batch::create_async, CreateBatch);
+    spanner_db_method!(validate_batch, batch::validate_async, ValidateBatch);
+    spanner_db_method!(append_to_batch, batch::append_async, AppendToBatch);
+    spanner_db_method!(
+        get_batch,
+        batch::get_async,
+        GetBatch,
+        Option<results::GetBatch>
+    );
+    spanner_db_method!(get_batch_usage, batch::get_usage_async, GetBatchUsage);
+    spanner_db_method!(commit_batch, batch::commit_async, CommitBatch);
 
     #[cfg(test)]
     fn get_collection_id(&self, name: String) -> DbFuture<'_, i32> {
@@ -1840,10 +1787,7 @@ impl<'a> Db<'a> for SpannerDb<'a> {
     }
 
     #[cfg(test)]
-    fn delete_batch(&self, param: params::DeleteBatch) -> DbFuture<'_, results::DeleteBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::delete_async(&db, param).map_err(Into::into).await })
-    }
+    spanner_db_method!(delete_batch, batch::delete_async, DeleteBatch);
 
     #[cfg(test)]
     fn clear_coll_cache(&self) {
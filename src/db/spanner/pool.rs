@@ -8,11 +8,13 @@ use std::{
 };
 
 use super::models::Result;
-use crate::db::{error::DbError, results, Db, DbPool, STD_COLLS};
+use crate::db::{
+    error::DbError, results, slow_query::SlowQueryLog, Db, DbPool, PoolTimeouts, STD_COLLS,
+};
 use crate::server::metrics::Metrics;
 use crate::settings::Settings;
 
-use super::manager::{SpannerConnectionManager, SpannerSession};
+use super::manager::{delete_session, SpannerConnectionManager, SpannerSession};
 use super::models::SpannerDb;
 use crate::error::ApiResult;
 
@@ -33,6 +35,10 @@ pub struct SpannerDbPool {
     pool: Pool<SpannerConnectionManager<SpannerSession>>,
     /// In-memory cache of collection_ids and their names
     coll_cache: Arc<CollectionCache>,
+    /// Logging of slow db calls
+    slow_query: SlowQueryLog,
+    /// The pool's maximum number of connections
+    max_size: u32,
 
     metrics: Metrics,
 }
@@ -47,23 +53,41 @@ impl SpannerDbPool {
     pub async fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
         let manager = SpannerConnectionManager::<SpannerSession>::new(settings)?;
         let max_size = settings.database_pool_max_size.unwrap_or(10);
+        let timeouts = PoolTimeouts::new(settings);
         let builder = bb8::Pool::builder()
             .max_size(max_size)
-            .min_idle(settings.database_pool_min_idle);
+            .min_idle(settings.database_pool_min_idle)
+            .connection_timeout(timeouts.connection_timeout)
+            .idle_timeout(timeouts.idle_timeout)
+            .max_lifetime(timeouts.max_lifetime)
+            .test_on_check_out(timeouts.test_on_checkout);
 
         Ok(Self {
             pool: builder.build(manager).await?,
             coll_cache: Default::default(),
+            slow_query: SlowQueryLog::new(&settings.slow_query, metrics),
+            max_size,
             metrics: metrics.clone(),
         })
     }
 
+    /// Check out a session, timing the wait in the "storage.pool.get" metric
+    /// and counting timeouts in "storage.pool.timeout"
     pub async fn get_async(&self) -> Result<SpannerDb<'_>> {
-        let conn = self.pool.get().await?;
+        let mut timer = self.metrics.clone();
+        timer.start_timer("storage.pool.get", None);
+        let conn = self.pool.get().await.map_err(|e| {
+            if let bb8::RunError::TimedOut = e {
+                self.metrics.incr("storage.pool.timeout");
+            }
+            e
+        })?;
+        drop(timer);
         Ok(SpannerDb::new(
             conn,
             Arc::clone(&self.coll_cache),
             &self.metrics,
+            &self.slow_query,
         ))
     }
 }
@@ -78,7 +102,10 @@ impl DbPool for SpannerDbPool {
     }
 
     fn state(&self) -> results::PoolState {
-        self.pool.state().into()
+        results::PoolState {
+            max_size: self.max_size,
+            ..self.pool.state().into()
+        }
     }
 
     fn validate_batch_id(&self, id: String) -> Result<()> {
@@ -88,6 +115,30 @@ impl DbPool for SpannerDbPool {
     fn box_clone(&self) -> Box<dyn DbPool> {
         Box::new(self.clone())
     }
+
+    /// Delete the idle sessions, checking them all out first so none are
+    /// handed out meanwhile. Sessions still in use are left to expire.
+    async fn close(&self) {
+        let idle = self.pool.state().idle_connections;
+        let mut conns = Vec::with_capacity(idle as usize);
+        for _ in 0..idle {
+            match self.pool.get().await {
+                Ok(conn) => conns.push(conn),
+                Err(e) => {
+                    warn!("Could not check out a session to close: {:?}", e);
+                    break;
+                }
+            }
+        }
+        let mut deleted = 0;
+        for conn in &conns {
+            match delete_session(conn).await {
+                Ok(()) => deleted += 1,
+                Err(e) => warn!("Could not delete session: {:?}", e),
+            }
+        }
+        info!("Deleted {} of {} Spanner sessions", deleted, idle);
+    }
 }
 
 impl fmt::Debug for SpannerDbPool {
@@ -2,12 +2,13 @@ use std::{
     collections::{HashMap, VecDeque},
     mem,
     result::Result as StdResult,
+    time::Instant,
 };
 
 use futures::stream::{StreamExt, StreamFuture};
 use googleapis_raw::spanner::v1::{
     result_set::{PartialResultSet, ResultSetMetadata, ResultSetStats},
-    spanner::ExecuteSqlRequest,
+    spanner::{ExecuteSqlRequest, ExecuteSqlRequest_QueryMode},
     type_pb::{StructType_Field, Type, TypeCode},
 };
 use grpcio::ClientSStreamReceiver;
@@ -17,10 +18,11 @@ use protobuf::{
 };
 
 use super::models::{Conn, Result};
-use crate::db::{results, util::SyncTimestamp, DbError, DbErrorKind};
+use crate::db::{results, slow_query::SlowQueryLog, util::SyncTimestamp, DbError, DbErrorKind};
 
 use crate::{
     db::{params, spanner::models::DEFAULT_BSO_TTL, util::to_rfc3339},
+    tracing::Span,
     web::extractors::HawkIdentifier,
 };
 
@@ -66,6 +68,10 @@ pub struct ExecuteSqlRequestBuilder {
     execute_sql: ExecuteSqlRequest,
     params: Option<HashMap<String, Value>>,
     param_types: Option<HashMap<String, Type>>,
+    /// Span of the request, ended with its execution
+    span: Span,
+    /// Logs the query stats and plan of the request, when profiled and slow
+    slow_query: Option<SlowQueryLog>,
 }
 
 impl ExecuteSqlRequestBuilder {
@@ -86,6 +92,27 @@ impl ExecuteSqlRequestBuilder {
         self
     }
 
+    pub fn span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Execute in PROFILE mode, logging the query stats and plan when slow
+    pub fn profile(mut self, slow_query: SlowQueryLog) -> Self {
+        self.execute_sql
+            .set_query_mode(ExecuteSqlRequest_QueryMode::PROFILE);
+        self.slow_query = Some(slow_query);
+        self
+    }
+
+    fn take_profile(&mut self) -> Option<Profile> {
+        self.slow_query.take().map(|slow_query| Profile {
+            slow_query,
+            sql: self.execute_sql.get_sql().to_owned(),
+            start: Instant::now(),
+        })
+    }
+
     fn prepare_request(self, conn: &Conn<'_>) -> ExecuteSqlRequest {
         let mut request = self.execute_sql;
         request.set_session(conn.session.get_name().to_owned());
@@ -101,23 +128,78 @@ impl ExecuteSqlRequestBuilder {
     }
 
     /// Execute a SQL read statement but return a non-blocking streaming result
-    pub fn execute_async(self, conn: &Conn<'_>) -> Result<StreamedResultSetAsync> {
+    pub fn execute_async(mut self, conn: &Conn<'_>) -> Result<StreamedResultSetAsync> {
+        let span = mem::take(&mut self.span);
+        let profile = self.take_profile();
         let stream = conn
             .client
             .execute_streaming_sql(&self.prepare_request(conn))?;
-        Ok(StreamedResultSetAsync::new(stream))
+        let mut result_set = StreamedResultSetAsync::new(stream);
+        // Ends once the results are consumed (dropped)
+        result_set.span = span;
+        result_set.profile = profile;
+        Ok(result_set)
     }
 
     /// Execute a DML statement, returning the exact count of modified rows
-    pub async fn execute_dml_async(self, conn: &Conn<'_>) -> Result<i64> {
+    pub async fn execute_dml_async(mut self, conn: &Conn<'_>) -> Result<i64> {
+        let _span = mem::take(&mut self.span);
+        let profile = self.take_profile();
         let rs = conn
             .client
             .execute_sql_async(&self.prepare_request(conn))?
             .await?;
+        if let Some(profile) = profile {
+            profile.finish(Some(rs.get_stats()));
+        }
         Ok(rs.get_stats().get_row_count_exact())
     }
 }
 
+/// A statement executed in PROFILE mode
+struct Profile {
+    slow_query: SlowQueryLog,
+    sql: String,
+    start: Instant,
+}
+
+impl Profile {
+    /// Log the statement's query `stats` and plan if it was slow
+    fn finish(&self, stats: Option<&ResultSetStats>) {
+        let elapsed = self.start.elapsed();
+        let stats = match stats {
+            Some(stats) if self.slow_query.is_slow(elapsed) => stats,
+            _ => return,
+        };
+        let mut query_stats: Vec<_> = stats
+            .get_query_stats()
+            .get_fields()
+            .iter()
+            .map(|(name, value)| {
+                if value.has_number_value() {
+                    format!("{}={}", name, value.get_number_value())
+                } else {
+                    format!("{}={}", name, value.get_string_value())
+                }
+            })
+            .collect();
+        query_stats.sort();
+        let query_plan: Vec<_> = stats
+            .get_query_plan()
+            .get_plan_nodes()
+            .iter()
+            .map(|node| format!("{}:{}", node.get_index(), node.get_display_name()))
+            .collect();
+        warn!(
+            "🐢 Slow Spanner statement";
+            "sql" => &self.sql,
+            "elapsed_ms" => elapsed.as_millis() as u64,
+            "query_stats" => query_stats.join(", "),
+            "query_plan" => query_plan.join(", "),
+        );
+    }
+}
+
 pub struct StreamedResultSetAsync {
     /// Stream from execute_streaming_sql
     stream: Option<StreamFuture<ClientSStreamReceiver<PartialResultSet>>>,
@@ -131,6 +213,17 @@ pub struct StreamedResultSetAsync {
     current_row: Vec<Value>,
     /// Incomplete value
     pending_chunk: Option<Value>,
+
+    span: Span,
+    profile: Option<Profile>,
+}
+
+impl Drop for StreamedResultSetAsync {
+    fn drop(&mut self) {
+        if let Some(ref profile) = self.profile {
+            profile.finish(self.stats.as_ref());
+        }
+    }
 }
 
 impl StreamedResultSetAsync {
@@ -142,6 +235,8 @@ impl StreamedResultSetAsync {
             rows: Default::default(),
             current_row: vec![],
             pending_chunk: None,
+            span: Span::default(),
+            profile: None,
         }
     }
 
@@ -1,6 +1,6 @@
 use log::debug;
 
-use super::support::{db_pool, gbso, hid, postbso, test_db, Result};
+use super::support::{db_pool, gbso, hid, pbso, postbso, test_db, Result};
 use crate::{
     db::{error::DbErrorKind, params, util::SyncTimestamp, BATCH_LIFETIME},
     error::ApiErrorKind,
@@ -157,3 +157,103 @@ async fn append_commit() -> Result<()> {
     assert_eq!(bso.payload, "payload 1");
     Ok(())
 }
+
+#[tokio::test]
+async fn commit_updates_existing() -> Result<()> {
+    let pool = db_pool().await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "b0", Some("payload 0"), Some(10), None))
+        .await?;
+
+    let bsos = vec![
+        postbso("b0", None, Some(11), None),
+        postbso("b1", Some("payload 1"), None, None),
+    ];
+    let id = db.create_batch(cb(uid, coll, bsos)).await?;
+    let batch = db.get_batch(gb(uid, coll, id)).await?.unwrap();
+    db.commit_batch(params::CommitBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        batch,
+    })
+    .await?;
+
+    let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
+    assert_eq!(bso.sortindex, Some(11));
+    assert_eq!(bso.payload, "payload 0");
+    let bso = db.get_bso(gbso(uid, coll, "b1")).await?.unwrap();
+    assert_eq!(bso.sortindex, None);
+    assert_eq!(bso.payload, "payload 1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn usage() -> Result<()> {
+    let pool = db_pool().await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let id = db.create_batch(cb(uid, coll, vec![])).await?;
+    let usage = db
+        .get_batch_usage(params::GetBatchUsage {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: id.clone(),
+            excluding: vec![],
+            excluding_payloads: vec![],
+        })
+        .await?;
+    assert_eq!(usage.count, 0);
+    assert_eq!(usage.total_bytes, 0);
+
+    let bsos = vec![
+        postbso("b0", Some("payload 0"), Some(10), None),
+        postbso("b1", None, Some(1), None),
+    ];
+    db.append_to_batch(ab(uid, coll, id.clone(), bsos)).await?;
+    let bsos = vec![postbso("b2", Some("payload 22"), None, None)];
+    db.append_to_batch(ab(uid, coll, id.clone(), bsos)).await?;
+
+    let usage = db
+        .get_batch_usage(params::GetBatchUsage {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: id.clone(),
+            excluding: vec![],
+            excluding_payloads: vec![],
+        })
+        .await?;
+    assert_eq!(usage.count, 3);
+    assert_eq!(usage.total_bytes, 19);
+
+    // Items about to be re-sent are left out
+    let usage = db
+        .get_batch_usage(params::GetBatchUsage {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id: id.clone(),
+            excluding: vec!["b0".to_owned(), "b3".to_owned()],
+            excluding_payloads: vec!["b0".to_owned(), "b3".to_owned()],
+        })
+        .await?;
+    assert_eq!(usage.count, 2);
+    assert_eq!(usage.total_bytes, 10);
+
+    // Unless re-sent without a payload, keeping their pending one
+    let usage = db
+        .get_batch_usage(params::GetBatchUsage {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            id,
+            excluding: vec!["b0".to_owned(), "b3".to_owned()],
+            excluding_payloads: vec!["b3".to_owned()],
+        })
+        .await?;
+    assert_eq!(usage.count, 2);
+    assert_eq!(usage.total_bytes, 19);
+    Ok(())
+}
@@ -222,6 +222,50 @@ async fn get_bsos_limit_offset() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_bsos_keyset_offset() -> Result<()> {
+    let pool = db_pool().await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    // Several items share a timestamp, so the id must break ties
+    for (i, delta) in [0, 0, 0, 10, 10].iter().enumerate() {
+        let bso = pbso(
+            uid,
+            coll,
+            &format!("b{}", i),
+            Some("a"),
+            None,
+            Some(DEFAULT_BSO_TTL),
+        );
+        with_delta!(&db, *delta, { db.put_bso(bso).await })?;
+    }
+
+    for (sort, expected) in &[
+        (Sorting::Newest, vec!["b4", "b3", "b2", "b1", "b0"]),
+        (Sorting::Oldest, vec!["b0", "b1", "b2", "b3", "b4"]),
+    ] {
+        let mut ids = vec![];
+        let mut offset = "0".to_owned();
+        loop {
+            let bsos = db
+                .get_bsos(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, *sort, 2, &offset))
+                .await?;
+            ids.extend(bsos.items.into_iter().map(|bso| bso.id));
+            match bsos.offset {
+                Some(next) => {
+                    assert!(next.contains(':'));
+                    offset = next;
+                }
+                None => break,
+            }
+        }
+        assert_eq!(&ids, expected);
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_bsos_newer() -> Result<()> {
     let pool = db_pool().await?;
@@ -1,11 +1,12 @@
 use crate::db::{params, Db, DbPool};
 use crate::error::{ApiError, ApiErrorKind};
 use crate::server::metrics::Metrics;
-use crate::server::ServerState;
+use crate::server::{shutdown::Drain, ServerState};
+use crate::tracing::{Span, SpanContext, SpanKind};
 use crate::web::extractors::{
     BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt,
 };
-use crate::web::middleware::SyncServerRequest;
+use crate::web::middleware::{access_log::RequestStats, SyncServerRequest};
 use crate::web::tags::Tags;
 use crate::web::X_LAST_MODIFIED;
 use actix_http::http::{HeaderValue, Method, StatusCode};
@@ -17,17 +18,40 @@ use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct DbTransactionPool {
     pool: Box<dyn DbPool>,
-    lock_collection: Option<params::LockCollection>,
+    lock_collections: Vec<params::LockCollection>,
     is_read: bool,
     tags: Tags,
     user_id: HawkIdentifier,
     collection: Option<String>,
     bso_opt: Option<String>,
+    method: Method,
     precondition: PreConditionHeaderOpt,
+    /// The request's span, parent of the transaction's
+    span_context: Option<SpanContext>,
+    /// The request's access log stats, when logged
+    stats: Option<Arc<RequestStats>>,
+    /// In-flight transactions, drained on shutdown
+    drain: Arc<Drain>,
+}
+
+/// Adds the time until it's dropped to the request's db time
+struct DbTimer {
+    stats: Option<Arc<RequestStats>>,
+    start: Instant,
+}
+
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        if let Some(ref stats) = self.stats {
+            stats.add_db_time(self.start.elapsed());
+        }
+    }
 }
 
 impl DbTransactionPool {
@@ -37,6 +61,7 @@ impl DbTransactionPool {
     /// action has succeeded (ex. check HTTP response for internal error).
     async fn transaction_internal<'a, A: 'a, R, F>(
         &'a self,
+        span: &Span,
         action: A,
     ) -> Result<(R, Box<dyn Db<'a>>), Error>
     where
@@ -48,17 +73,33 @@ impl DbTransactionPool {
         let db2 = db.clone();
 
         // Lock for transaction
-        let result = match (self.lock_collection.clone(), self.is_read) {
-            (Some(lc), true) => db.lock_for_read(lc).await,
-            (Some(lc), false) => db.lock_for_write(lc).await,
-            _ => Ok(()),
-        };
+        for lc in self.lock_collections.clone() {
+            let mut lock_span = Span::start(
+                if self.is_read {
+                    "lock_for_read"
+                } else {
+                    "lock_for_write"
+                },
+                SpanKind::Internal,
+                span.context().as_ref(),
+            );
+            lock_span.set_attribute("collection", lc.collection.as_str());
+            db.set_span_context(lock_span.context());
+            let result = if self.is_read {
+                db.lock_for_read(lc).await
+            } else {
+                db.lock_for_write(lc).await
+            };
+            db.set_span_context(span.context());
 
-        // Handle lock error
-        if let Err(e) = result {
-            db.rollback().await?;
-            return Err(e.into());
+            // Handle lock error
+            if let Err(e) = result {
+                lock_span.set_error(&e.to_string());
+                db.rollback().await?;
+                return Err(e.into());
+            }
         }
+        db.set_span_context(span.context());
 
         // XXX: lock_for_x usually begins transactions but Dbs may also
         // implicitly create them, so commit/rollback are always called to
@@ -77,13 +118,55 @@ impl DbTransactionPool {
         Ok(self.pool.clone())
     }
 
+    /// Write lock all of the specified collections (in addition to the
+    /// request's own) for the transaction, so their changes are applied
+    /// atomically.
+    ///
+    /// Collections are locked in name order to avoid deadlocking against
+    /// other multi-collection writers.
+    pub fn with_write_locks(mut self, collections: Vec<String>) -> Self {
+        for collection in collections {
+            self.lock_collections.push(params::LockCollection {
+                user_id: self.user_id.clone(),
+                collection,
+            });
+        }
+        self.lock_collections
+            .sort_by(|a, b| a.collection.cmp(&b.collection));
+        self.lock_collections
+            .dedup_by(|a, b| a.collection == b.collection);
+        self.is_read = false;
+        self
+    }
+
+    /// Start the span of a transaction, a child of the request's
+    fn span(&self) -> Span {
+        let mut span = Span::start(
+            "transaction",
+            SpanKind::Internal,
+            self.span_context.as_ref(),
+        );
+        span.set_attribute("transaction.read", self.is_read);
+        span
+    }
+
+    fn timer(&self) -> DbTimer {
+        DbTimer {
+            stats: self.stats.clone(),
+            start: Instant::now(),
+        }
+    }
+
     /// Perform an action inside of a DB transaction.
     pub async fn transaction<'a, A: 'a, R, F>(&'a self, action: A) -> Result<R, Error>
     where
         A: FnOnce(Box<dyn Db<'a>>) -> F,
         F: Future<Output = Result<R, Error>> + 'a,
     {
-        let (resp, db) = self.transaction_internal(action).await?;
+        let _in_flight = self.drain.transaction();
+        let span = self.span();
+        let _timer = self.timer();
+        let (resp, db) = self.transaction_internal(&span, action).await?;
 
         // No further processing before commit is possible
         db.commit().await?;
@@ -119,12 +202,30 @@ impl DbTransactionPool {
                         {
                             StatusCode::PRECONDITION_FAILED
                         }
+                        PreConditionHeader::IfNoneMatch(etags) if etags.matches(resource_ts) => {
+                            match self.method {
+                                Method::GET | Method::HEAD => StatusCode::NOT_MODIFIED,
+                                _ => StatusCode::PRECONDITION_FAILED,
+                            }
+                        }
+                        PreConditionHeader::IfMatch(etags) if !etags.matches(resource_ts) => {
+                            StatusCode::PRECONDITION_FAILED
+                        }
                         _ => StatusCode::OK,
                     };
                     if status != StatusCode::OK {
                         return Ok(HttpResponse::build(status)
                             .content_type("application/json")
                             .header(X_LAST_MODIFIED, resource_ts.as_header())
+                            .if_true(self.collection.is_some(), |resp| {
+                                // Only a BSO has a single representation
+                                let etag = if self.bso_opt.is_some() {
+                                    resource_ts.as_etag()
+                                } else {
+                                    resource_ts.as_weak_etag()
+                                };
+                                resp.header(header::ETAG, etag);
+                            })
                             .body("".to_owned())
                             .into_body());
                     };
@@ -147,7 +248,10 @@ impl DbTransactionPool {
             }
         };
 
-        let (resp, db) = self.transaction_internal(check_precondition).await?;
+        let _in_flight = self.drain.transaction();
+        let span = self.span();
+        let _timer = self.timer();
+        let (resp, db) = self.transaction_internal(&span, check_precondition).await?;
 
         // HttpResponse can contain an internal error
         match resp.error() {
@@ -222,21 +326,25 @@ impl FromRequest for DbTransactionPool {
                     _ => false,
                 };
 
-                (Some(lc), is_read)
+                (vec![lc], is_read)
             } else {
-                (None, true)
+                (vec![], true)
             };
-            let collection = lc.as_ref().map(|c| c.collection.clone());
+            let collection = lc.first().map(|c| c.collection.clone());
             let precondition = PreConditionHeaderOpt::extrude(&req.headers(), Some(tags.clone()))?;
             let pool = Self {
                 pool: state.db_pool.clone(),
-                lock_collection: lc,
+                lock_collections: lc,
                 is_read,
                 tags,
                 user_id,
                 collection,
                 bso_opt,
+                method,
                 precondition,
+                span_context: req.extensions().get::<SpanContext>().copied(),
+                stats: RequestStats::get(&req),
+                drain: Arc::clone(&state.drain),
             };
 
             req.extensions_mut().insert(pool.clone());
@@ -13,6 +13,10 @@ use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
 
 use super::{DbError, DbErrorKind};
 
+/// Suffixes of the entity tags of compressed representations, per content
+/// coding (see `web::middleware::compression`)
+const ETAG_CODINGS: [&str; 2] = ["-gzip", "-br"];
+
 /// Get the time since the UNIX epoch in milliseconds
 pub fn ms_since_epoch() -> i64 {
     Utc::now().timestamp_millis()
@@ -34,6 +38,34 @@ impl SyncTimestamp {
         format_ts(self.0)
     }
 
+    /// Create a strong entity tag (ETag) value for the timestamp
+    pub fn as_etag(self) -> String {
+        format!("\"{}\"", self.0)
+    }
+
+    /// Create a weak entity tag value for the timestamp, for resources with
+    /// several representations (a collection's pages and formats)
+    pub fn as_weak_etag(self) -> String {
+        format!("W/{}", self.as_etag())
+    }
+
+    /// Create a `SyncTimestamp` from an entity tag created by `as_etag`,
+    /// possibly suffixed with the content coding of a compressed
+    /// representation (e.g. `"1234-gzip"`)
+    pub fn from_etag(val: &str) -> Result<Self, &'static str> {
+        if val.len() < 2 || !val.starts_with('"') || !val.ends_with('"') {
+            return Err("Invalid entity tag");
+        }
+        let tag = &val[1..val.len() - 1];
+        let tag = ETAG_CODINGS
+            .iter()
+            .find(|coding| tag.ends_with(*coding))
+            .map_or(tag, |coding| &tag[..tag.len() - coding.len()]);
+        tag.parse::<u64>()
+            .map(SyncTimestamp)
+            .map_err(|_| "Invalid entity tag")
+    }
+
     /// Create a `SyncTimestamp` from a string header
     ///
     /// Assumes the string represents the seconds since epoch with two decimal places of precision.
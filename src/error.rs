@@ -74,6 +74,12 @@ pub enum ApiErrorKind {
 
     #[fail(display = "{}", _0)]
     Validation(#[cause] ValidationError),
+
+    #[fail(display = "Missing or invalid admin token")]
+    AdminUnauthorized,
+
+    #[fail(display = "The admin endpoints are disabled")]
+    AdminDisabled,
 }
 
 impl ApiError {
@@ -208,6 +214,8 @@ impl From<Context<ApiErrorKind>> for ApiError {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
             ApiErrorKind::Validation(error) => error.status,
+            ApiErrorKind::AdminUnauthorized => StatusCode::UNAUTHORIZED,
+            ApiErrorKind::AdminDisabled => StatusCode::FORBIDDEN,
         };
 
         Self { inner, status }
@@ -268,6 +276,9 @@ impl Serialize for ApiErrorKind {
             ApiErrorKind::NoServerState => {
                 Serialize::serialize("No State information found", serializer)
             }
+            ApiErrorKind::AdminUnauthorized | ApiErrorKind::AdminDisabled => {
+                serialize_string_to_array(serializer, self)
+            }
         }
     }
 }
@@ -1,11 +1,21 @@
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::error::ApiResult;
 
 use slog::{self, slog_o, Drain};
 use slog_mozlog_json::MozLogJson;
 
+/// Whether logs are in the mozlog json format, kept by `set_log_filter`
+static JSON: AtomicBool = AtomicBool::new(false);
+
 pub fn init_logging(json: bool) -> ApiResult<()> {
+    init_logging_with_filter(json, None)
+}
+
+/// Initialize logging, filtering records by `filter` (in `RUST_LOG`'s
+/// syntax) when specified, otherwise by `RUST_LOG`
+pub fn init_logging_with_filter(json: bool, filter: Option<&str>) -> ApiResult<()> {
     let logger = if json {
         let hostname = hostname::get()
             .expect("Couldn't get hostname")
@@ -22,13 +32,13 @@ pub fn init_logging(json: bool) -> ApiResult<()> {
             .hostname(hostname)
             .build()
             .fuse();
-        let drain = slog_envlogger::new(drain);
+        let drain = env_logger(drain, filter);
         let drain = slog_async::Async::new(drain).build().fuse();
         slog::Logger::root(drain, slog_o!())
     } else {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_envlogger::new(drain);
+        let drain = env_logger(drain, filter);
         let drain = slog_async::Async::new(drain).build().fuse();
         slog::Logger::root(drain, slog_o!())
     };
@@ -38,9 +48,22 @@ pub fn init_logging(json: bool) -> ApiResult<()> {
     // https://github.com/slog-rs/slog/issues/169
     slog_scope::set_global_logger(logger).cancel_reset();
     slog_stdlog::init().ok();
+    JSON.store(json, Ordering::Relaxed);
     Ok(())
 }
 
+/// Replace the log filter (e.g. on reload), keeping the logs' format
+pub fn set_log_filter(filter: Option<&str>) -> ApiResult<()> {
+    init_logging_with_filter(JSON.load(Ordering::Relaxed), filter)
+}
+
+fn env_logger<D: Drain>(drain: D, filter: Option<&str>) -> slog_envlogger::EnvLogger<D> {
+    match filter {
+        Some(filter) => slog_envlogger::LogBuilder::new(drain).parse(filter).build(),
+        None => slog_envlogger::new(drain),
+    }
+}
+
 pub fn reset_logging() {
     let logger = slog::Logger::root(slog::Discard, slog_o!());
     slog_scope::set_global_logger(logger).cancel_reset();
@@ -3,12 +3,14 @@
 extern crate slog_scope;
 
 use std::error::Error;
+use std::net::ToSocketAddrs;
+use std::process;
 
 use docopt::Docopt;
 use serde_derive::Deserialize;
 
-use logging::init_logging;
-use syncstorage::{logging, server, settings};
+use logging::init_logging_with_filter;
+use syncstorage::{db, error::ApiError, logging, server, server::metrics::Metrics, settings};
 
 const USAGE: &str = "
 Usage: syncstorage [options]
@@ -16,11 +18,53 @@ Usage: syncstorage [options]
 Options:
     -h, --help               Show this message.
     --config=CONFIGFILE      Syncstorage configuration file path.
+    --check-config           Validate the configuration, print it (with secrets
+                             redacted) and exit, non-zero if it's invalid.
+    --check-db               With --check-config, also check the database is
+                             reachable.
+    --print-config-schema    Print every setting's key, environment variable,
+                             type and default as JSON and exit.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_config: Option<String>,
+    flag_check_config: bool,
+    flag_check_db: bool,
+    flag_print_config_schema: bool,
+}
+
+/// Validate the settings, printing them (with secrets redacted) and any
+/// problems found. Returns whether there were none.
+async fn check_config(settings: &settings::Settings, check_db: bool) -> bool {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&settings.redacted()).expect("Couldn't serialize settings")
+    );
+    let mut problems = settings.errors();
+    problems.extend(settings.warnings());
+    if let Some(ref host) = settings.statsd_host {
+        if let Err(e) = (host.as_str(), settings.statsd_port).to_socket_addrs() {
+            problems.push(format!("statsd_host {:?} doesn't resolve: {}", host, e));
+        }
+    }
+    if check_db && problems.is_empty() {
+        let result = async {
+            let pool = db::pool_from_settings(settings, &Metrics::noop()).await?;
+            let db = pool.get().await?;
+            Ok::<_, ApiError>(db.check().await?)
+        }
+        .await;
+        match result {
+            Ok(true) => (),
+            Ok(false) => problems.push("database check failed without error".to_owned()),
+            Err(e) => problems.push(format!("database is unreachable: {}", e)),
+        }
+    }
+    for problem in &problems {
+        eprintln!("Configuration problem: {}", problem);
+    }
+    problems.is_empty()
 }
 
 #[actix_rt::main]
@@ -28,8 +72,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
-    let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
-    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    if args.flag_print_config_schema {
+        let schema = settings::Settings::schema()?;
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+    let settings = match settings::Settings::with_env_and_config_file(&args.flag_config) {
+        Ok(settings) => settings,
+        Err(e) if args.flag_check_config => {
+            eprintln!("Configuration problem: {}", e);
+            process::exit(1);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    init_logging_with_filter(!settings.human_logs, settings.log_level.as_deref())
+        .expect("Logging failed to initialize");
+    if args.flag_check_config {
+        let valid = check_config(&settings, args.flag_check_db).await;
+        logging::reset_logging();
+        process::exit(if valid { 0 } else { 1 });
+    }
     debug!("Starting up...");
     // Set SENTRY_DSN environment variable to enable Sentry.
     // Avoid its default reqwest transport for now due to issues w/
@@ -50,7 +112,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Setup and run the server
     let banner = settings.banner();
-    let server = server::Server::with_settings(settings).await.unwrap();
+    let server = server::Server::with_settings(settings, args.flag_config)
+        .await
+        .unwrap();
     info!("Server running on {}", banner);
     server.await?;
     info!("Server closing");
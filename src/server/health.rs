@@ -0,0 +1,273 @@
+//! The per-dependency checks reported by `__heartbeat__`.
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use actix_web::web::block;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::db::{
+    results::{PoolState, SchemaVersion},
+    DbPool,
+};
+use crate::error::ApiError;
+use crate::settings::{Heartbeat, Settings};
+
+/// The status of a check, ordered by severity
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: Level,
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+impl Check {
+    fn new(status: Level, details: Value) -> Self {
+        let details = match details {
+            Value::Object(details) => details,
+            _ => Map::new(),
+        };
+        Check { status, details }
+    }
+}
+
+/// The results of all the checks, by name
+#[derive(Debug, Default, Serialize)]
+pub struct HealthReport {
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl HealthReport {
+    /// The most severe status of the checks
+    pub fn status(&self) -> Level {
+        self.checks
+            .values()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Level::Ok)
+    }
+}
+
+/// Checks of the database, its pool and schema, statsd and the settings.
+#[derive(Debug)]
+pub struct Health {
+    thresholds: Heartbeat,
+    /// Warnings of the settings, determined at startup
+    config_warnings: Vec<String>,
+    statsd: Option<(String, u16)>,
+}
+
+impl Health {
+    pub fn new(settings: &Settings) -> Self {
+        Health {
+            thresholds: settings.heartbeat,
+            config_warnings: settings.warnings(),
+            statsd: settings
+                .statsd_host
+                .clone()
+                .map(|host| (host, settings.statsd_port)),
+        }
+    }
+
+    /// Whether a report of `status` fails the heartbeat
+    pub fn is_fatal(&self, status: Level) -> bool {
+        match status {
+            Level::Ok => false,
+            Level::Warn => self.thresholds.warnings_fatal,
+            Level::Fail => true,
+        }
+    }
+
+    pub async fn check(&self, db_pool: &dyn DbPool) -> HealthReport {
+        let mut checks = BTreeMap::new();
+        checks.insert("pool", self.check_pool(&db_pool.state()));
+        let (database, schema) = self.check_database(db_pool).await;
+        checks.insert("database", database);
+        checks.insert("schema", schema);
+        checks.insert("statsd", self.check_statsd().await);
+        checks.insert("config", self.check_config());
+        HealthReport { checks }
+    }
+
+    fn check_pool(&self, state: &PoolState) -> Check {
+        let saturation = state.saturation();
+        let status = match saturation {
+            Some(saturation)
+                if saturation >= u32::from(self.thresholds.pool_saturation_warn_percentage) =>
+            {
+                Level::Warn
+            }
+            _ => Level::Ok,
+        };
+        Check::new(
+            status,
+            json!({
+                "connections": state.connections,
+                "idle_connections": state.idle_connections,
+                "max_connections": state.max_size,
+                "saturation_percentage": saturation,
+            }),
+        )
+    }
+
+    /// Check the database's latency and its schema's version
+    async fn check_database(&self, db_pool: &dyn DbPool) -> (Check, Check) {
+        let start = Instant::now();
+        let result = async {
+            let db = db_pool.get().await?;
+            let up = db.check().await?;
+            let latency = start.elapsed();
+            let schema = db.schema_version().await?;
+            Ok::<_, ApiError>((up, latency, schema))
+        }
+        .await;
+
+        match result {
+            Ok((true, latency, schema)) => {
+                let threshold = Duration::from_millis(self.thresholds.db_latency_warn_ms.into());
+                let status = if latency > threshold {
+                    Level::Warn
+                } else {
+                    Level::Ok
+                };
+                let latency_ms = latency.as_millis() as u64;
+                (
+                    Check::new(status, json!({ "latency_ms": latency_ms })),
+                    check_schema(schema),
+                )
+            }
+            Ok((false, _, _)) => (
+                Check::new(Level::Fail, json!({"msg": "check failed without error"})),
+                Check::new(Level::Warn, json!({"msg": "database unavailable"})),
+            ),
+            Err(e) => {
+                error!("Heartbeat error: {:?}", e);
+                (
+                    Check::new(Level::Fail, json!({ "msg": e.to_string() })),
+                    Check::new(Level::Warn, json!({"msg": "database unavailable"})),
+                )
+            }
+        }
+    }
+
+    /// Check that statsd's address resolves (its metrics are sent over UDP,
+    /// so they can't be confirmed as received)
+    async fn check_statsd(&self) -> Check {
+        let (host, port) = match self.statsd.clone() {
+            Some(statsd) => statsd,
+            None => return Check::new(Level::Ok, json!({"msg": "disabled"})),
+        };
+        let address = format!("{}:{}", host, port);
+        let result = block(move || -> io::Result<()> {
+            let addr = (host.as_str(), port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+            let socket = UdpSocket::bind(if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })?;
+            socket.connect(addr)
+        })
+        .await;
+        match result {
+            Ok(()) => Check::new(Level::Ok, json!({ "address": address })),
+            Err(e) => Check::new(
+                Level::Warn,
+                json!({"address": address, "msg": e.to_string()}),
+            ),
+        }
+    }
+
+    fn check_config(&self) -> Check {
+        if self.config_warnings.is_empty() {
+            Check::new(Level::Ok, json!({}))
+        } else {
+            Check::new(Level::Warn, json!({ "msg": self.config_warnings }))
+        }
+    }
+}
+
+/// Check that the latest migration was applied. A newer one (from a newer
+/// release) is only warned of.
+fn check_schema(schema: Option<SchemaVersion>) -> Check {
+    let schema = match schema {
+        Some(schema) => schema,
+        None => return Check::new(Level::Ok, json!({"msg": "unmanaged"})),
+    };
+    let status = match schema.applied {
+        Some(ref applied) if *applied == schema.latest => Level::Ok,
+        Some(ref applied) if *applied > schema.latest => Level::Warn,
+        _ => Level::Fail,
+    };
+    Check::new(
+        status,
+        json!({"version": schema.applied, "expected": schema.latest}),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_schema, Health, Level};
+    use crate::db::results::{PoolState, SchemaVersion};
+    use crate::settings::Settings;
+
+    fn schema(applied: Option<&str>) -> Option<SchemaVersion> {
+        Some(SchemaVersion {
+            applied: applied.map(ToOwned::to_owned),
+            latest: "20200612231034".to_owned(),
+        })
+    }
+
+    #[test]
+    fn test_check_schema() {
+        assert_eq!(check_schema(None).status, Level::Ok);
+        assert_eq!(
+            check_schema(schema(Some("20200612231034"))).status,
+            Level::Ok
+        );
+        assert_eq!(
+            check_schema(schema(Some("20210101000000"))).status,
+            Level::Warn
+        );
+        assert_eq!(
+            check_schema(schema(Some("20200403102015"))).status,
+            Level::Fail
+        );
+        assert_eq!(check_schema(schema(None)).status, Level::Fail);
+    }
+
+    #[test]
+    fn test_check_pool() {
+        let health = Health::new(&Settings::default());
+        let state = |connections, idle_connections| PoolState {
+            connections,
+            idle_connections,
+            max_size: 10,
+        };
+        assert_eq!(health.check_pool(&state(5, 2)).status, Level::Ok);
+        assert_eq!(health.check_pool(&state(10, 2)).status, Level::Warn);
+    }
+
+    #[test]
+    fn test_is_fatal() {
+        let mut settings = Settings::default();
+        let health = Health::new(&settings);
+        assert!(!health.is_fatal(Level::Ok));
+        assert!(!health.is_fatal(Level::Warn));
+        assert!(health.is_fatal(Level::Fail));
+        settings.heartbeat.warnings_fatal = true;
+        assert!(Health::new(&settings).is_fatal(Level::Warn));
+    }
+}
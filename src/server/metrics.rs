@@ -1,4 +1,5 @@
 use std::net::UdpSocket;
+use std::sync::Arc;
 use std::time::Instant;
 
 use actix_web::{error::ErrorInternalServerError, web::Data, Error, HttpRequest};
@@ -7,7 +8,7 @@ use cadence::{
 };
 
 use crate::error::ApiError;
-use crate::server::ServerState;
+use crate::server::{prometheus::PrometheusRegistry, ServerState};
 use crate::settings::Settings;
 use crate::web::tags::Tags;
 
@@ -16,6 +17,8 @@ pub struct MetricTimer {
     pub label: String,
     pub start: Instant,
     pub tags: Tags,
+    /// Also recorded as a db operation in `/__metrics__`
+    pub db_operation: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -23,11 +26,19 @@ pub struct Metrics {
     client: Option<StatsdClient>,
     tags: Option<Tags>,
     timer: Option<MetricTimer>,
+    /// Also records db method timers as db operations, when `/__metrics__` is
+    /// enabled
+    prometheus: Option<Arc<PrometheusRegistry>>,
 }
 
 impl Drop for Metrics {
     fn drop(&mut self) {
         let tags = self.tags.clone().unwrap_or_default();
+        if let (Some(prometheus), Some(timer)) = (self.prometheus.as_ref(), self.timer.as_ref()) {
+            if timer.db_operation {
+                prometheus.record_db_operation(&timer.label, timer.start.elapsed());
+            }
+        }
         if let Some(client) = self.client.as_ref() {
             if let Some(timer) = self.timer.as_ref() {
                 let lapse = (Instant::now() - timer.start).as_millis() as u64;
@@ -59,16 +70,15 @@ impl From<&HttpRequest> for Metrics {
         let exts = req.extensions();
         let def_tags = Tags::from_request_head(req.head());
         let tags = exts.get::<Tags>().unwrap_or_else(|| &def_tags);
+        let state = req.app_data::<Data<ServerState>>();
+        if state.is_none() {
+            warn!("⚠️ metric error: No App State");
+        }
         Metrics {
-            client: match req.app_data::<Data<ServerState>>() {
-                Some(v) => Some(*v.metrics.clone()),
-                None => {
-                    warn!("⚠️ metric error: No App State");
-                    None
-                }
-            },
+            client: state.as_ref().map(|state| *state.metrics.clone()),
             tags: Some(tags.clone()),
             timer: None,
+            prometheus: state.and_then(|state| state.prometheus.clone()),
         }
     }
 }
@@ -79,6 +89,7 @@ impl From<&StatsdClient> for Metrics {
             client: Some(client.clone()),
             tags: None,
             timer: None,
+            prometheus: None,
         }
     }
 }
@@ -89,6 +100,7 @@ impl From<&ServerState> for Metrics {
             client: Some(*state.metrics.clone()),
             tags: None,
             timer: None,
+            prometheus: state.prometheus.clone(),
         }
     }
 }
@@ -103,9 +115,16 @@ impl Metrics {
             client: Some(Self::sink()),
             timer: None,
             tags: None,
+            prometheus: None,
         }
     }
 
+    /// Also record timers in the `/__metrics__` registry
+    pub fn with_prometheus(mut self, prometheus: Option<Arc<PrometheusRegistry>>) -> Self {
+        self.prometheus = prometheus;
+        self
+    }
+
     pub fn start_timer(&mut self, label: &str, tags: Option<Tags>) {
         let mut mtags = self.tags.clone().unwrap_or_default();
         if let Some(t) = tags {
@@ -117,17 +136,35 @@ impl Metrics {
             label: label.to_owned(),
             start: Instant::now(),
             tags: mtags,
+            db_operation: false,
         });
     }
 
+    /// Start the timer of a db method, also recorded as a db operation
+    pub fn start_db_timer(&mut self, label: &str) {
+        self.start_timer(label, None);
+        if let Some(timer) = self.timer.as_mut() {
+            timer.db_operation = true;
+        }
+    }
+
     // increment a counter with no tags data.
     pub fn incr(&self, label: &str) {
         self.incr_with_tags(label, None)
     }
 
     pub fn incr_with_tags(&self, label: &str, tags: Option<Tags>) {
+        self.count_with_tags(label, 1, tags)
+    }
+
+    // increment a counter by an arbitrary value with no tags data.
+    pub fn count(&self, label: &str, value: i64) {
+        self.count_with_tags(label, value, None)
+    }
+
+    pub fn count_with_tags(&self, label: &str, value: i64, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
-            let mut tagged = client.incr_with_tags(label);
+            let mut tagged = client.count_with_tags(label, value);
             let mut mtags = self.tags.clone().unwrap_or_default();
             if let Some(tags) = tags {
                 mtags.extend(tags.tags);
@@ -229,4 +266,23 @@ mod tests {
         assert!(!tags.tags.contains_key("ua.os.ver"));
         println!("{:?}", tags);
     }
+
+    #[test]
+    fn test_db_timers_only_recorded_as_db_operations() {
+        use crate::db::results::PoolState;
+
+        let prometheus = Arc::new(PrometheusRegistry::default());
+        let metrics = Metrics::noop().with_prometheus(Some(Arc::clone(&prometheus)));
+        {
+            let mut timer = metrics.clone();
+            timer.start_timer("storage.pool.get", None);
+        }
+        {
+            let mut timer = metrics.clone();
+            timer.start_db_timer("storage.sql.get_bsos");
+        }
+        let out = prometheus.render(&PoolState::default());
+        assert!(out.contains(r#"operation="storage.sql.get_bsos""#));
+        assert!(!out.contains("storage.pool.get"));
+    }
 }
@@ -1,25 +1,45 @@
 //! Main application server
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    io,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 use crate::db::{pool_from_settings, spawn_pool_periodic_reporter, DbPool};
 use crate::error::ApiError;
-use crate::server::metrics::Metrics;
-use crate::settings::{Secrets, ServerLimits, Settings};
-use crate::web::{handlers, middleware, tokenserver};
+use crate::server::{
+    health::Health,
+    metrics::Metrics,
+    prometheus::PrometheusRegistry,
+    reload::{Reloadable, Reloader},
+    shutdown::Drain,
+};
+use crate::settings::{AccessLog, Backoff, ResponseCompression, Secrets, ServerLimits, Settings};
+use crate::tracing;
+use crate::web::{
+    handlers, middleware,
+    middleware::{ratelimit::RateLimiter, rejectua::RejectUARules},
+    tokenserver,
+};
 use actix_cors::Cors;
 use actix_web::{
-    dev, http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpRequest,
-    HttpResponse, HttpServer,
+    http::StatusCode, middleware::errhandlers::ErrorHandlers, web, App, HttpRequest, HttpResponse,
+    HttpServer,
 };
 use cadence::StatsdClient;
+use futures::future::LocalBoxFuture;
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
-const SYNC_VERSION_PATH: &str = "1.5";
+pub const SYNC_VERSION_PATH: &str = "1.5";
 
+pub mod health;
 pub mod metrics;
+pub mod prometheus;
+pub mod reload;
+pub mod shutdown;
 #[cfg(test)]
 mod test;
 pub mod user_agent;
@@ -30,7 +50,7 @@ pub struct ServerState {
     pub db_pool: Box<dyn DbPool>,
 
     /// Server-enforced limits for request payloads.
-    pub limits: Arc<ServerLimits>,
+    pub limits: Reloadable<ServerLimits>,
 
     /// Secrets used during Hawk authentication.
     pub secrets: Arc<Secrets>,
@@ -38,7 +58,41 @@ pub struct ServerState {
     /// Metric reporting
     pub metrics: Box<StatsdClient>,
 
+    /// Metrics exposed via `/__metrics__`, when enabled.
+    pub prometheus: Option<Arc<PrometheusRegistry>>,
+
     pub port: u16,
+
+    /// Negotiated compression of storage responses.
+    pub response_compression: ResponseCompression,
+
+    /// The access log's settings.
+    pub access_log: AccessLog,
+
+    /// Per-user and per-IP rate limits, shared between workers.
+    pub rate_limiter: Arc<RateLimiter>,
+
+    /// Rules rejecting requests by User-Agent.
+    pub reject_ua: Reloadable<RejectUARules>,
+
+    /// Server-driven client backoff.
+    pub backoff: Reloadable<Backoff>,
+
+    /// Whether storage writes are rejected, shared between workers.
+    pub read_only: Arc<AtomicBool>,
+
+    /// Bearer token authorizing requests to the `/__admin__` endpoints.
+    pub admin_token: Option<String>,
+
+    /// The `__heartbeat__` checks.
+    pub health: Arc<Health>,
+
+    /// In-flight db transactions, drained on shutdown.
+    pub drain: Arc<Drain>,
+
+    /// Reloads the limits, rejected User-Agents, log level, backoff and rate
+    /// limits.
+    pub reloader: Arc<Reloader>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -65,7 +119,14 @@ macro_rules! build_app {
             // .wrap(middleware::db::DbTransaction::new())
             .wrap(middleware::weave::WeaveTimestamp::new())
             .wrap(middleware::sentry::SentryWrapper::new())
+            .wrap(middleware::backoff::ClientBackoff::default())
+            .wrap(middleware::readonly::ReadOnly::default())
             .wrap(middleware::rejectua::RejectUA::default())
+            .wrap(middleware::ratelimit::RateLimiting::default())
+            .wrap(middleware::compression::Compression::default())
+            .wrap(middleware::prometheus::PrometheusMetrics::default())
+            .wrap(middleware::tracing::Tracing::default())
+            .wrap(middleware::access_log::AccessLogger::default())
             // Followed by the "official middleware" so they run first.
             .wrap(Cors::default())
             .service(
@@ -89,7 +150,9 @@ macro_rules! build_app {
             )
             .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
             .service(
-                web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
+                web::resource(&cfg_path("/storage"))
+                    .route(web::delete().to(handlers::delete_all))
+                    .route(web::post().to(handlers::post_storage_transaction)),
             )
             .service(
                 web::resource(&cfg_path("/storage/{collection}"))
@@ -128,14 +191,7 @@ macro_rules! build_app {
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
             // when applying changes to endpoint names.
             .service(web::resource("/__heartbeat__").route(web::get().to(handlers::heartbeat)))
-            .service(
-                web::resource("/__lbheartbeat__").route(web::get().to(|_: HttpRequest| {
-                    // used by the load balancers, just return OK.
-                    HttpResponse::Ok()
-                        .content_type("application/json")
-                        .body("{}")
-                })),
-            )
+            .service(web::resource("/__lbheartbeat__").route(web::get().to(handlers::lbheartbeat)))
             .service(
                 web::resource("/__version__").route(web::get().to(|_: HttpRequest| {
                     // return the contents of the version.json file created by circleci
@@ -146,34 +202,90 @@ macro_rules! build_app {
                 })),
             )
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+            .service(
+                web::resource("/__metrics__").route(web::get().to(handlers::prometheus_metrics)),
+            )
+            // Admin
+            .service(
+                web::resource("/__admin__/read_only")
+                    .route(web::get().to(handlers::get_read_only))
+                    .route(web::put().to(handlers::put_read_only)),
+            )
+            .service(web::resource("/__admin__/reload").route(web::post().to(handlers::reload)))
     };
 }
 
 impl Server {
-    pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
+    /// Start the server, reloading `config_file` (and the environment) on
+    /// SIGHUP. The returned future completes once it has shut down.
+    pub async fn with_settings(
+        settings: Settings,
+        config_file: Option<String>,
+    ) -> Result<LocalBoxFuture<'static, io::Result<()>>, ApiError> {
         let metrics = metrics::metrics_from_opts(&settings)?;
-        let db_pool = pool_from_settings(&settings, &Metrics::from(&metrics)).await?;
-        let limits = Arc::new(settings.limits);
+        tracing::init_tracing(&settings.tracing)?;
+        let prometheus = if settings.prometheus_enabled {
+            Some(Arc::new(PrometheusRegistry::default()))
+        } else {
+            None
+        };
+        let db_pool = pool_from_settings(
+            &settings,
+            &Metrics::from(&metrics).with_prometheus(prometheus.clone()),
+        )
+        .await?;
+        let reloader = Arc::new(Reloader::new(&settings, config_file)?);
+        // The request body limits, which only change on restart
+        let limits = Arc::new(settings.limits.clone());
         let secrets = Arc::new(settings.master_secret);
         let port = settings.port;
+        let response_compression = settings.response_compression;
+        let access_log = settings.access_log;
+        let read_only = Arc::new(AtomicBool::new(settings.read_only));
+        let admin_token = settings.admin_token.clone();
+        let health = Arc::new(Health::new(&settings));
+        let drain = Arc::new(Drain::default());
 
         spawn_pool_periodic_reporter(Duration::from_secs(10), metrics.clone(), db_pool.clone())?;
+        let shutdown_pool = db_pool.clone();
+        let shutdown_drain = Arc::clone(&drain);
+        reload::spawn_signal_handler(Arc::clone(&reloader));
 
         let server = HttpServer::new(move || {
             // Setup the server state
             let state = ServerState {
                 db_pool: db_pool.clone(),
-                limits: Arc::clone(&limits),
+                limits: reloader.limits.clone(),
                 secrets: Arc::clone(&secrets),
                 metrics: Box::new(metrics.clone()),
+                prometheus: prometheus.clone(),
                 port,
+                response_compression,
+                access_log,
+                rate_limiter: Arc::clone(&reloader.rate_limiter),
+                reject_ua: reloader.reject_ua.clone(),
+                backoff: reloader.backoff.clone(),
+                read_only: Arc::clone(&read_only),
+                admin_token: admin_token.clone(),
+                health: Arc::clone(&health),
+                drain: Arc::clone(&drain),
+                reloader: Arc::clone(&reloader),
             };
 
             build_app!(state, limits)
         })
         .bind(format!("{}:{}", settings.host, settings.port))
         .expect("Could not get Server in Server::with_settings")
+        // Signals are handled by shutdown::spawn_signal_handler
+        .disable_signals()
+        .shutdown_timeout(settings.shutdown.drain_timeout_secs.into())
         .run();
-        Ok(server)
+        shutdown::spawn_signal_handler(server.clone(), shutdown_drain, settings.shutdown);
+        // Close the pool's sessions once the workers have stopped
+        Ok(Box::pin(async move {
+            let result = server.await;
+            shutdown_pool.close().await;
+            result
+        }))
     }
 }
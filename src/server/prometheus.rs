@@ -0,0 +1,267 @@
+//! An in-process registry of metrics exposed to Prometheus via `/__metrics__`,
+//! for deployments without a statsd pipeline.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::db::results::PoolState;
+
+const REQUESTS: &str = "syncstorage_requests_total";
+const REQUEST_DURATION: &str = "syncstorage_request_duration_seconds";
+const DB_OPERATION_DURATION: &str = "syncstorage_db_operation_duration_seconds";
+
+const BUCKET_COUNT: usize = 12;
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; BUCKET_COUNT] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// A metric family's name and a set of its label values
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+struct Key {
+    name: &'static str,
+    labels: Vec<(&'static str, String)>,
+}
+
+impl Key {
+    fn new(name: &'static str, labels: &[(&'static str, &str)]) -> Self {
+        Key {
+            name,
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, (*value).to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Render the labels, with any additional `le` bucket label
+    fn render_labels(&self, le: Option<&str>) -> String {
+        let mut labels: Vec<_> = self
+            .labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        if let Some(le) = le {
+            labels.push(format!("le=\"{}\"", le));
+        }
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Observations per bucket (not cumulative)
+    buckets: [u64; BUCKET_COUNT],
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKET_COUNT],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Escape a label value per the text exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Write the HELP and TYPE lines of a metric family
+fn write_family(out: &mut String, name: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help(name));
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn help(name: &str) -> &'static str {
+    match name {
+        REQUESTS => "Requests handled, by endpoint and status",
+        REQUEST_DURATION => "Request latency, by endpoint and status",
+        DB_OPERATION_DURATION => "Database operation latency, by operation",
+        _ => "",
+    }
+}
+
+/// Counters and histograms recorded by all workers.
+#[derive(Debug, Default)]
+pub struct PrometheusRegistry {
+    counters: Mutex<BTreeMap<Key, u64>>,
+    histograms: Mutex<BTreeMap<Key, Histogram>>,
+}
+
+impl PrometheusRegistry {
+    fn incr(&self, key: Key) {
+        let mut counters = self.counters.lock().expect("Prometheus counters poisoned");
+        *counters.entry(key).or_insert(0) += 1;
+    }
+
+    fn observe(&self, key: Key, duration: Duration) {
+        let mut histograms = self
+            .histograms
+            .lock()
+            .expect("Prometheus histograms poisoned");
+        histograms
+            .entry(key)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Record a handled request
+    pub fn record_request(&self, endpoint: &str, method: &str, status: u16, duration: Duration) {
+        let status = status.to_string();
+        let labels = [
+            ("endpoint", endpoint),
+            ("method", method),
+            ("status", status.as_str()),
+        ];
+        self.incr(Key::new(REQUESTS, &labels));
+        self.observe(Key::new(REQUEST_DURATION, &labels), duration);
+    }
+
+    /// Record a timed database operation
+    pub fn record_db_operation(&self, operation: &str, duration: Duration) {
+        self.observe(
+            Key::new(DB_OPERATION_DURATION, &[("operation", operation)]),
+            duration,
+        );
+    }
+
+    /// Render all metrics, along with gauges of the db pool's `state`, in the
+    /// Prometheus text exposition format
+    pub fn render(&self, state: &PoolState) -> String {
+        let mut out = String::new();
+
+        let counters = self.counters.lock().expect("Prometheus counters poisoned");
+        let mut last = None;
+        for (key, value) in counters.iter() {
+            if last != Some(key.name) {
+                write_family(&mut out, key.name, "counter");
+                last = Some(key.name);
+            }
+            let _ = writeln!(out, "{}{} {}", key.name, key.render_labels(None), value);
+        }
+        drop(counters);
+
+        let histograms = self
+            .histograms
+            .lock()
+            .expect("Prometheus histograms poisoned");
+        let mut last = None;
+        for (key, histogram) in histograms.iter() {
+            if last != Some(key.name) {
+                write_family(&mut out, key.name, "histogram");
+                last = Some(key.name);
+            }
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let le = bound.to_string();
+                let labels = key.render_labels(Some(&le));
+                let _ = writeln!(out, "{}_bucket{} {}", key.name, labels, cumulative);
+            }
+            let labels = key.render_labels(Some("+Inf"));
+            let _ = writeln!(out, "{}_bucket{} {}", key.name, labels, histogram.count);
+            let labels = key.render_labels(None);
+            let _ = writeln!(out, "{}_sum{} {}", key.name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", key.name, labels, histogram.count);
+        }
+        drop(histograms);
+
+        for (name, description, value) in &[
+            (
+                "syncstorage_db_pool_connections",
+                "Open database connections",
+                state.connections,
+            ),
+            (
+                "syncstorage_db_pool_idle_connections",
+                "Idle database connections",
+                state.idle_connections,
+            ),
+            (
+                "syncstorage_db_pool_max_connections",
+                "Maximum database connections (0 when unknown)",
+                state.max_size,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, description);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PrometheusRegistry;
+    use crate::db::results::PoolState;
+
+    #[test]
+    fn test_render() {
+        let registry = PrometheusRegistry::default();
+        let endpoint = "/1.5/{uid}/storage/{collection}";
+        registry.record_request(endpoint, "GET", 200, Duration::from_millis(20));
+        registry.record_request(endpoint, "GET", 200, Duration::from_millis(200));
+        registry.record_request(endpoint, "POST", 503, Duration::from_secs(60));
+        registry.record_db_operation("storage.sql.get_bsos", Duration::from_millis(3));
+        let out = registry.render(&PoolState {
+            connections: 5,
+            idle_connections: 3,
+            max_size: 10,
+        });
+
+        let labels = r#"endpoint="/1.5/{uid}/storage/{collection}",method="GET",status="200""#;
+        for line in &[
+            "# TYPE syncstorage_requests_total counter".to_owned(),
+            format!("syncstorage_requests_total{{{}}} 2", labels),
+            "# TYPE syncstorage_request_duration_seconds histogram".to_owned(),
+            format!(
+                "syncstorage_request_duration_seconds_bucket{{{},le=\"0.025\"}} 1",
+                labels
+            ),
+            format!(
+                "syncstorage_request_duration_seconds_bucket{{{},le=\"0.25\"}} 2",
+                labels
+            ),
+            format!(
+                "syncstorage_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                labels
+            ),
+            format!("syncstorage_request_duration_seconds_count{{{}}} 2", labels),
+            r#"syncstorage_request_duration_seconds_bucket{endpoint="/1.5/{uid}/storage/{collection}",method="POST",status="503",le="30"} 0"#.to_owned(),
+            r#"syncstorage_db_operation_duration_seconds_bucket{operation="storage.sql.get_bsos",le="0.005"} 1"#.to_owned(),
+            "syncstorage_db_pool_connections 5".to_owned(),
+            "syncstorage_db_pool_idle_connections 3".to_owned(),
+            "syncstorage_db_pool_max_connections 10".to_owned(),
+        ] {
+            assert!(out.lines().any(|l| l == line.as_str()), "missing {:?} in:\n{}", line, out);
+        }
+        // One HELP/TYPE per family
+        assert_eq!(out.matches("# TYPE syncstorage_requests_total").count(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+//! Reloading the settings safe to change at runtime, on SIGHUP or via the
+//! `/__admin__/reload` endpoint.
+use std::sync::{Arc, Mutex, RwLock};
+
+use actix_rt::signal::unix::{signal, SignalKind};
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::logging;
+use crate::settings::{Backoff, ServerLimits, Settings};
+use crate::web::middleware::{ratelimit::RateLimiter, rejectua::RejectUARules};
+
+/// A value shared between workers, swapped atomically on reload
+#[derive(Debug)]
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Reloadable(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// The current value
+    pub fn load(&self) -> Arc<T> {
+        Arc::clone(&self.0.read().expect("Reloadable value poisoned"))
+    }
+
+    pub fn store(&self, value: T) {
+        *self.0.write().expect("Reloadable value poisoned") = Arc::new(value);
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Reloadable(Arc::clone(&self.0))
+    }
+}
+
+/// Reloads the server limits, rejected User-Agents, log level, backoff and
+/// rate limits from the config file and environment.
+///
+/// The other settings (notably the database url and secrets) are left
+/// untouched until a restart.
+#[derive(Debug)]
+pub struct Reloader {
+    config_file: Option<String>,
+    /// Enforced while reading request bodies, as configured at startup
+    max_request_bytes: u32,
+    database_url: String,
+    master_secret: Vec<u8>,
+    pub limits: Reloadable<ServerLimits>,
+    pub reject_ua: Reloadable<RejectUARules>,
+    pub backoff: Reloadable<Backoff>,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Serializes reloads, so one's settings are applied together
+    reloading: Mutex<()>,
+}
+
+impl Reloader {
+    pub fn new(settings: &Settings, config_file: Option<String>) -> Result<Self, ApiError> {
+        Ok(Self {
+            config_file,
+            max_request_bytes: settings.limits.max_request_bytes,
+            database_url: settings.database_url.clone(),
+            master_secret: settings.master_secret.master_secret.clone(),
+            limits: Reloadable::new(settings.limits.clone()),
+            reject_ua: Reloadable::new(RejectUARules::new(&settings.reject_ua)?),
+            backoff: Reloadable::new(settings.backoff.clone()),
+            rate_limiter: Arc::new(RateLimiter::new(settings.rate_limits.clone())),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    /// Reload the settings, applying them only once all are validated
+    pub fn reload(&self) -> Result<(), ApiError> {
+        let settings = Settings::with_env_and_config_file(&self.config_file)
+            .map_err(|e| ApiErrorKind::Internal(format!("Invalid settings: {}", e)))?;
+        self.apply(settings)
+    }
+
+    fn apply(&self, settings: Settings) -> Result<(), ApiError> {
+        let _reloading = self.reloading.lock().expect("Reloader poisoned");
+        if settings.limits.max_request_bytes != self.max_request_bytes {
+            Err(ApiErrorKind::Internal(
+                "limits.max_request_bytes can't change without a restart".to_owned(),
+            ))?;
+        }
+        // Unlike at startup, inconsistent limits aren't only warned of
+        let mut errors = settings.errors();
+        errors.extend(settings.limits.warnings());
+        if !errors.is_empty() {
+            Err(ApiErrorKind::Internal(errors.join("; ")))?;
+        }
+        let reject_ua = RejectUARules::new(&settings.reject_ua)?;
+        if settings.database_url != self.database_url
+            || settings.master_secret.master_secret != self.master_secret
+        {
+            warn!("Ignoring changes to database_url or master_secret until a restart");
+        }
+
+        logging::set_log_filter(settings.log_level.as_deref())?;
+        self.limits.store(settings.limits);
+        self.reject_ua.store(reject_ua);
+        self.backoff.store(settings.backoff);
+        self.rate_limiter.set_limits(settings.rate_limits);
+        info!("Settings reloaded");
+        Ok(())
+    }
+}
+
+/// Reload the settings on each SIGHUP
+pub fn spawn_signal_handler(reloader: Arc<Reloader>) {
+    actix_rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Could not install the reload signal handler: {:?}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reloader.reload() {
+                error!("Could not reload the settings: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reloader;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_apply() {
+        let settings = Settings::default();
+        let reloader = Reloader::new(&settings, None).unwrap();
+
+        let mut reloaded = settings.clone();
+        reloaded.limits.max_post_records = 7;
+        reloaded.backoff.seconds = 42;
+        reloaded.rate_limits.enabled = true;
+        reloaded.database_url = "mysql://elsewhere/syncstorage".to_owned();
+        reloader.apply(reloaded.clone()).unwrap();
+        assert_eq!(reloader.limits.load().max_post_records, 7);
+        assert_eq!(reloader.backoff.load().seconds, 42);
+        assert!(reloader.rate_limiter.limits().enabled);
+
+        // Nothing's applied when any setting's invalid
+        let mut invalid = reloaded.clone();
+        invalid.limits.max_post_records = 8;
+        invalid.rate_limits.status = 1000;
+        assert!(reloader.apply(invalid).is_err());
+        assert_eq!(reloader.limits.load().max_post_records, 7);
+
+        let mut inconsistent = reloaded.clone();
+        inconsistent.limits.max_post_bytes = inconsistent.limits.max_request_bytes + 1;
+        assert!(reloader.apply(inconsistent).is_err());
+        assert_eq!(
+            reloader.limits.load().max_post_bytes,
+            reloaded.limits.max_post_bytes
+        );
+
+        let mut restart = reloaded;
+        restart.limits.max_request_bytes += 1;
+        assert!(reloader.apply(restart).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Coordinated shutdown, draining in-flight requests.
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use actix_rt::{
+    signal::unix::{signal, SignalKind},
+    time::delay_for,
+};
+use actix_web::dev;
+use futures::future::{self, FutureExt};
+
+use crate::settings::Shutdown;
+
+/// How often the in-flight transactions are checked while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tracks in-flight db transactions, shared between workers, and whether the
+/// server is draining requests to shut down.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    transactions: AtomicUsize,
+}
+
+impl Drain {
+    /// Whether the server is shutting down (failing `__lbheartbeat__`)
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Count a transaction as in-flight until the returned guard is dropped
+    pub fn transaction(self: &Arc<Self>) -> InFlight {
+        self.transactions.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            drain: Arc::clone(self),
+        }
+    }
+
+    /// The number of in-flight transactions
+    pub fn in_flight(&self) -> usize {
+        self.transactions.load(Ordering::SeqCst)
+    }
+
+    /// Begin draining, failing `__lbheartbeat__`
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+}
+
+/// An in-flight transaction
+#[derive(Debug)]
+pub struct InFlight {
+    drain: Arc<Drain>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.drain.transactions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Shut `server` down on SIGTERM or SIGINT: fail `__lbheartbeat__` while
+/// still serving requests for `Shutdown::pre_stop_delay_secs` (letting load
+/// balancers pull the node), wait for the in-flight transactions to finish,
+/// then refuse new connections and stop the workers. The whole drain is
+/// bounded by `Shutdown::drain_timeout_secs`.
+pub fn spawn_signal_handler(server: dev::Server, drain: Arc<Drain>, shutdown: Shutdown) {
+    actix_rt::spawn(async move {
+        let signals = signal(SignalKind::terminate())
+            .and_then(|term| Ok((term, signal(SignalKind::interrupt())?)));
+        let (mut term, mut int) = match signals {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("Could not install the shutdown signal handlers: {:?}", e);
+                return;
+            }
+        };
+        future::select(term.recv().boxed_local(), int.recv().boxed_local()).await;
+
+        info!("Shutting down, draining {} transactions", drain.in_flight());
+        drain.start();
+        let timeout = Duration::from_secs(shutdown.drain_timeout_secs.into());
+        let deadline = Instant::now() + timeout;
+        let pre_stop_delay = Duration::from_secs(shutdown.pre_stop_delay_secs.into());
+        delay_for(pre_stop_delay.min(timeout)).await;
+        while drain.in_flight() > 0 && Instant::now() < deadline {
+            delay_for(DRAIN_POLL_INTERVAL).await;
+        }
+        let in_flight = drain.in_flight();
+        if in_flight > 0 {
+            warn!(
+                "Drain timed out, stopping with {} in-flight transactions",
+                in_flight
+            );
+        }
+        server.stop(true).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Drain;
+
+    #[test]
+    fn test_drain() {
+        let drain = Arc::new(Drain::default());
+        assert!(!drain.is_draining());
+        let first = drain.transaction();
+        let second = drain.transaction();
+        assert_eq!(drain.in_flight(), 2);
+        drop(first);
+        drain.start();
+        assert!(drain.is_draining());
+        assert_eq!(drain.in_flight(), 1);
+        drop(second);
+        assert_eq!(drain.in_flight(), 0);
+    }
+}
@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
 
 use actix_web::{
     dev::Service,
@@ -22,12 +24,13 @@ use crate::db::params;
 use crate::db::pool_from_settings;
 use crate::db::results::{DeleteBso, GetBso, PostBsos, PutBso};
 use crate::db::util::SyncTimestamp;
-use crate::settings::{Secrets, ServerLimits};
+use crate::settings::{
+    AccessLog, Backoff, BackoffMode, ResponseCompression, Secrets, ServerLimits,
+};
 use crate::web::auth::HawkPayload;
 use crate::web::extractors::BsoBody;
 
 lazy_static! {
-    static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
     static ref SECRETS: Arc<Secrets> =
         Arc::new(Secrets::new("foo").expect("Could not get Secrets in server/test.rs"));
 }
@@ -65,14 +68,31 @@ fn get_test_settings() -> Settings {
 
 async fn get_test_state(settings: &Settings) -> ServerState {
     let metrics = Metrics::sink();
+    let reloader =
+        Arc::new(Reloader::new(settings, None).expect("Could not get reloader in get_test_state"));
     ServerState {
         db_pool: pool_from_settings(&settings, &Metrics::from(&metrics))
             .await
             .expect("Could not get db_pool in get_test_state"),
-        limits: Arc::clone(&SERVER_LIMITS),
+        limits: reloader.limits.clone(),
         secrets: Arc::clone(&SECRETS),
         metrics: Box::new(metrics),
+        prometheus: if settings.prometheus_enabled {
+            Some(Arc::new(PrometheusRegistry::default()))
+        } else {
+            None
+        },
         port: settings.port,
+        response_compression: settings.response_compression,
+        access_log: settings.access_log,
+        rate_limiter: Arc::clone(&reloader.rate_limiter),
+        reject_ua: reloader.reject_ua.clone(),
+        backoff: reloader.backoff.clone(),
+        read_only: Arc::new(AtomicBool::new(settings.read_only)),
+        admin_token: settings.admin_token.clone(),
+        health: Arc::new(Health::new(settings)),
+        drain: Arc::new(Drain::default()),
+        reloader,
     }
 }
 
@@ -517,6 +537,61 @@ async fn invalid_batch_post() {
     assert_eq!(body, "0");
 }
 
+#[actix_rt::test]
+async fn batch_total_bytes_limit() {
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        limits: ServerLimits {
+            max_post_bytes: 30,
+            max_total_bytes: 30,
+            ..ServerLimits::default()
+        },
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/tabs?batch=true",
+        None,
+        Some(json!([{"id": "a", "payload": "x".repeat(20)}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let path = format!(
+        "/1.5/42/storage/tabs?batch={}",
+        body["batch"].as_str().unwrap()
+    );
+
+    // Re-sending a payload replaces the pending one's bytes
+    let req = create_request(
+        http::Method::POST,
+        &path,
+        None,
+        Some(json!([{"id": "a", "payload": "x".repeat(25)}])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    // Re-sending without one keeps its bytes: 25 + 10 exceeds the limit
+    let req = create_request(
+        http::Method::POST,
+        &path,
+        None,
+        Some(json!([
+            {"id": "a", "sortindex": 1},
+            {"id": "b", "payload": "x".repeat(10)}
+        ])),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[actix_rt::test]
 async fn accept_new_or_dev_ios() {
     let mut app = init_app!().await;
@@ -604,3 +679,497 @@ async fn reject_old_ios() {
     let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
     assert_eq!(body, "0");
 }
+
+#[actix_rt::test]
+async fn post_storage_transaction() {
+    let start = SyncTimestamp::default();
+    let body = json!({
+        "bookmarks": {
+            "put": [{"id": "foo", "payload": "bar", "sortindex": 1}],
+            "delete": ["baz"]
+        },
+        "meta": {"put": [{"id": "global", "payload": "SomePayload"}]},
+        "crypto": {"put": [{"id": "keys", "payload": "SomeKeys"}]}
+    });
+    let bytes = test_endpoint_with_body(http::Method::POST, "/1.5/42/storage", body).await;
+    let result: serde_json::Value =
+        serde_json::from_slice(&bytes).expect("Could not get result in post_storage_transaction");
+    let modified: SyncTimestamp = serde_json::from_value(result["modified"].clone())
+        .expect("Could not get modified in post_storage_transaction");
+    assert!(modified >= start);
+    for collection in &["bookmarks", "meta", "crypto"] {
+        assert_eq!(result["collections"][collection], result["modified"]);
+    }
+}
+
+#[actix_rt::test]
+async fn invalid_storage_transaction() {
+    let mut app = init_app!().await;
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(json!({
+            "bookmarks": {"put": [{"id": "foo", "payload": "bar"}]},
+            "meta": {"put": [{"id": "global", "sortindex": "bad"}]}
+        })),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in invalid_storage_transaction");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The body's limited to max_request_bytes
+    let payload = "x".repeat(ServerLimits::default().max_request_bytes as usize);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(json!({"bookmarks": {"put": [{"id": "foo", "payload": payload}]}})),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in invalid_storage_transaction");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn bso_etag_preconditions() {
+    let mut app = init_app!().await;
+    let path = "/1.5/42/storage/bookmarks/etagged";
+
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        None,
+        Some(json!({"payload": "SomePayload"})),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in bso_etag_preconditions");
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response
+        .headers()
+        .get("etag")
+        .expect("Could not get etag in bso_etag_preconditions")
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let mut headers = HashMap::new();
+    headers.insert("If-None-Match", etag.clone());
+    let req = create_request(http::Method::GET, path, Some(headers), None).to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in bso_etag_preconditions");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get("etag").unwrap(), etag.as_str());
+
+    let mut headers = HashMap::new();
+    headers.insert("If-Match", "\"1000\"".to_owned());
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        Some(headers),
+        Some(json!({"payload": "Conflicting"})),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in bso_etag_preconditions");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+}
+
+#[actix_rt::test]
+async fn compressed_bso_etag_preconditions() {
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        response_compression: ResponseCompression {
+            enabled: true,
+            min_bytes: 0,
+        },
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    let path = "/1.5/42/storage/bookmarks/compressed";
+
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        None,
+        Some(json!({"payload": "x".repeat(4096)})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut headers = HashMap::new();
+    headers.insert("Accept-Encoding", "gzip".to_owned());
+    let req = create_request(http::Method::GET, path, Some(headers), None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    let etag = response
+        .headers()
+        .get("etag")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(!etag.starts_with("W/"));
+    assert!(etag.ends_with("-gzip\""));
+
+    // Echoing the compressed representation's tag satisfies If-Match
+    let mut headers = HashMap::new();
+    headers.insert("If-Match", etag);
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        Some(headers),
+        Some(json!({"payload": "updated"})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn collection_etag_preconditions() {
+    let mut app = init_app!().await;
+    let path = "/1.5/42/storage/bookmarks";
+
+    let req = create_request(
+        http::Method::POST,
+        path,
+        None,
+        Some(json!([{"id": "etagged", "payload": "SomePayload"}])),
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in collection_etag_preconditions");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(http::Method::GET, path, None, None).to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in collection_etag_preconditions");
+    let etag = response
+        .headers()
+        .get("etag")
+        .expect("Could not get etag in collection_etag_preconditions")
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(etag.starts_with("W/\""));
+
+    // Any page or format of the collection is weakly matched
+    let mut headers = HashMap::new();
+    headers.insert("If-None-Match", etag.clone());
+    let req = create_request(
+        http::Method::GET,
+        &format!("{}?limit=1", path),
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response = app
+        .call(req)
+        .await
+        .expect("Could not get response in collection_etag_preconditions");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get("etag").unwrap(), etag.as_str());
+}
+
+#[actix_rt::test]
+async fn read_only_mode() {
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        read_only: true,
+        admin_token: Some("s3cret".to_owned()),
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let path = "/1.5/42/storage/bookmarks/wibble";
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        None,
+        Some(json!({"payload": "wibble"})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+    assert!(response.headers().contains_key("x-weave-backoff"));
+    let req = create_request(http::Method::DELETE, path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // Reads keep working
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Toggling requires the admin token
+    let req = test::TestRequest::with_uri("/__admin__/read_only")
+        .method(http::Method::PUT)
+        .set_json(&json!({"read_only": false}))
+        .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::with_uri("/__admin__/read_only")
+        .method(http::Method::PUT)
+        .header("Authorization", "Bearer s3cret")
+        .set_json(&json!({"read_only": false}))
+        .to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(result, json!({"read_only": false}));
+
+    let req = create_request(
+        http::Method::PUT,
+        path,
+        None,
+        Some(json!({"payload": "wibble"})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn backoff_reject_mode() {
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        backoff: Backoff {
+            mode: BackoffMode::Reject,
+            user_percentage: 100,
+            ..Backoff::default()
+        },
+        admin_token: Some("s3cret".to_owned()),
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(json!({"payload": "wibble"})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // The admin endpoints aren't storage requests: they're never backed off
+    let req = test::TestRequest::with_uri("/__admin__/read_only")
+        .method(http::Method::PUT)
+        .header("Authorization", "Bearer s3cret")
+        .set_json(&json!({"read_only": true}))
+        .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("x-weave-backoff"));
+}
+
+#[actix_rt::test]
+async fn prometheus_metrics() {
+    let mut app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        prometheus_enabled: true,
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains(
+        r#"syncstorage_requests_total{endpoint="/1.5/{uid}/info/collections",method="GET",status="200"} 1"#
+    ));
+    assert!(body.contains("syncstorage_db_operation_duration_seconds_count"));
+    assert!(body.contains("syncstorage_db_pool_connections"));
+}
+
+#[actix_rt::test]
+async fn heartbeat() {
+    crate::logging::init_logging(false).unwrap();
+    // The test settings leave master_secret unset: a config warning
+    let mut settings = get_test_settings();
+    settings.heartbeat.warnings_fatal = true;
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    let req = test::TestRequest::with_uri("/__heartbeat__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let mut app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__heartbeat__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["status"], "Warn");
+    assert_eq!(body["database"], "Ok");
+    assert_eq!(body["checks"]["schema"]["status"], "ok");
+    assert_eq!(body["checks"]["statsd"]["status"], "ok");
+    assert_eq!(body["checks"]["config"]["status"], "warn");
+    assert_eq!(body["checks"]["config"]["msg"][0], "master_secret is unset");
+}
+
+/// A log drain capturing the fields of "request.summary" records
+struct AccessLogCapture(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+struct AccessLogFields(HashMap<String, String>);
+
+impl slog::Serializer for AccessLogFields {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments<'_>) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+impl slog::Drain for AccessLogCapture {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record<'_>, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        use slog::KV;
+
+        if record.msg().to_string() == "request.summary" {
+            let mut fields = AccessLogFields(HashMap::new());
+            record
+                .kv()
+                .serialize(record, &mut fields)
+                .expect("Could not serialize the access log record");
+            self.0.lock().unwrap().push(fields.0);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn access_log() {
+    crate::logging::init_logging(false).unwrap();
+    let records = Arc::new(Mutex::new(vec![]));
+    // Scoped to this thread, so other tests' loggers don't interfere
+    let logger = slog::Logger::root(AccessLogCapture(Arc::clone(&records)), slog::o!());
+    let body = slog_scope::scope(&logger, || {
+        actix_rt::System::new("access_log").block_on(async {
+            let settings = Settings {
+                access_log: AccessLog {
+                    enabled: true,
+                    sample_rate: 1.0,
+                },
+                ..get_test_settings()
+            };
+            let limits = Arc::new(settings.limits.clone());
+            let mut app =
+                test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+            let req = create_request(
+                http::Method::POST,
+                "/1.5/42/storage/bookmarks",
+                None,
+                Some(json!([
+                    {"id": "a", "payload": "xxx"},
+                    {"id": "b", "payload": "yyy"}
+                ])),
+            )
+            .to_request();
+            let response = app.call(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            test::read_body(response).await;
+
+            let req = create_request(http::Method::GET, "/1.5/42/storage/bookmarks", None, None)
+                .to_request();
+            let response = app.call(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            test::read_body(response).await
+        })
+    });
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    for record in records.iter() {
+        assert_eq!(record["path"], "/1.5/{uid}/storage/{collection}");
+        assert_eq!(record["collection"], "bookmarks");
+        assert_eq!(record["code"], "200");
+        assert_eq!(record["records"], "2");
+        let db_time_ms: u64 = record["db_time_ms"].parse().unwrap();
+        assert!(db_time_ms <= record["t"].parse().unwrap());
+        assert_ne!(record["uid"], "42");
+    }
+    assert_eq!(records[0]["method"], "POST");
+    assert_eq!(records[1]["method"], "GET");
+    assert_eq!(records[1]["bytes_out"], body.len().to_string());
+}
+
+#[actix_rt::test]
+async fn lbheartbeat_fails_while_draining() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let state = get_test_state(&settings).await;
+    let drain = Arc::clone(&state.drain);
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let req = test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    drain.start();
+    let req = test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_rt::test]
+async fn admin_reload() {
+    crate::logging::init_logging(false).unwrap();
+    let settings = Settings {
+        admin_token: Some("s3cret".to_owned()),
+        ..get_test_settings()
+    };
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let req = test::TestRequest::with_uri("/__admin__/reload")
+        .method(http::Method::POST)
+        .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::with_uri("/__admin__/reload")
+        .method(http::Method::POST)
+        .header("Authorization", "Bearer s3cret")
+        .to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(result, json!({"reloaded": true}));
+}
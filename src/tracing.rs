@@ -0,0 +1,623 @@
+//! Distributed tracing: spans propagated via W3C `traceparent` headers and
+//! exported to an OpenTelemetry collector via OTLP/HTTP (JSON encoding).
+//!
+//! Like `Metrics` timers, a `Span` is recorded when it's dropped. Spans are
+//! no-ops until `init_tracing` is called with tracing enabled.
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use curl::easy::{Easy, List};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::settings::Tracing;
+
+/// Finished spans buffered for the exporter, beyond which they're dropped
+const QUEUE_SIZE: usize = 4096;
+/// Most spans exported per request to the collector
+const MAX_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The bits of the `f64` fraction of new traces sampled
+static SAMPLE_RATIO: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<SyncSender<SpanData>>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// This thread's clone of the `EXPORTER`'s sender, so finished spans
+    /// don't contend for its lock
+    static SENDER: RefCell<Option<SyncSender<SpanData>>> = RefCell::new(None);
+}
+
+/// The identity of a span, as propagated via the `traceparent` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// Whether the trace is recorded
+    pub sampled: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+impl SpanContext {
+    /// Parse a W3C `traceparent` header: "{version}-{trace-id}-{parent-id}-{flags}"
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let (trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+        // Future versions may append fields, but version 00 has exactly 4
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let mut context = SpanContext {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+            sampled: false,
+        };
+        unhex(trace_id, &mut context.trace_id)?;
+        unhex(span_id, &mut context.span_id)?;
+        let mut flags_byte = [0; 1];
+        unhex(flags, &mut flags_byte)?;
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        context.sampled = flags_byte[0] & 1 == 1;
+        Some(context)
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(i64::from(value))
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+/// A timed operation within a trace, exported when dropped.
+#[derive(Debug, Default)]
+pub struct Span {
+    /// None when tracing's disabled
+    data: Option<SpanData>,
+}
+
+/// Whether `init_tracing` enabled tracing
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+impl Span {
+    /// Start a span, as a child of `parent` or otherwise the root of a new
+    /// trace (sampled at `tracing.sample_ratio`)
+    pub fn start(name: &str, kind: SpanKind, parent: Option<&SpanContext>) -> Self {
+        if !enabled() {
+            return Span::default();
+        }
+        let mut rng = rand::thread_rng();
+        let mut span_id = [0; 8];
+        while span_id == [0; 8] {
+            span_id = rng.gen();
+        }
+        let (trace_id, sampled) = match parent {
+            Some(parent) => (parent.trace_id, parent.sampled),
+            None => {
+                let mut trace_id = [0; 16];
+                while trace_id == [0; 16] {
+                    trace_id = rng.gen();
+                }
+                let sample_ratio = f64::from_bits(SAMPLE_RATIO.load(Ordering::Relaxed));
+                (trace_id, rng.gen::<f64>() < sample_ratio)
+            }
+        };
+        let now = SystemTime::now();
+        Span {
+            data: Some(SpanData {
+                context: SpanContext {
+                    trace_id,
+                    span_id,
+                    sampled,
+                },
+                parent_span_id: parent.map(|parent| parent.span_id),
+                name: name.to_owned(),
+                kind,
+                start: now,
+                end: now,
+                attributes: vec![],
+                error: None,
+            }),
+        }
+    }
+
+    /// The context of child spans, when tracing
+    pub fn context(&self) -> Option<SpanContext> {
+        self.data.as_ref().map(|data| data.context)
+    }
+
+    pub fn set_attribute<V: Into<AttributeValue>>(&mut self, key: &'static str, value: V) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    /// Mark the span as failed
+    pub fn set_error(&mut self, message: &str) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = Some(message.to_owned());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut data = match self.data.take() {
+            Some(data) if data.context.sampled => data,
+            _ => return,
+        };
+        data.end = SystemTime::now();
+        let _ = SENDER.try_with(|sender| {
+            let mut sender = sender.borrow_mut();
+            if sender.is_none() {
+                *sender = EXPORTER.lock().expect("Tracing exporter poisoned").clone();
+            }
+            if let Some(sender) = sender.as_ref() {
+                if sender.try_send(data).is_err() {
+                    trace!("Dropping span: export queue full");
+                }
+            }
+        });
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attribute_json(key: &str, value: &AttributeValue) -> Value {
+    let value = match value {
+        AttributeValue::String(v) => json!({ "stringValue": v }),
+        // int64s are strings in the JSON encoding
+        AttributeValue::Int(v) => json!({ "intValue": v.to_string() }),
+        AttributeValue::Bool(v) => json!({ "boolValue": v }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// An OTLP `ExportTraceServiceRequest` of `spans`
+fn export_request(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": hex(&span.context.trace_id),
+                "spanId": hex(&span.context.span_id),
+                "name": span.name,
+                "kind": span.kind as u8,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute_json(key, value))
+                    .collect::<Vec<_>>(),
+                "status": match span.error {
+                    Some(ref message) => json!({ "code": 2, "message": message }),
+                    None => json!({ "code": 0 }),
+                },
+            });
+            if let Some(parent_span_id) = span.parent_span_id {
+                value["parentSpanId"] = json!(hex(&parent_span_id));
+            }
+            value
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute_json("service.name", &service_name.into()),
+                    attribute_json("service.version", &env!("CARGO_PKG_VERSION").into()),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// POST a JSON `body` to `url`, returning the response's status and body
+fn post(handle: &mut Easy, url: &Url, body: &[u8]) -> Result<(u32, Vec<u8>), curl::Error> {
+    let mut headers = List::new();
+    headers.append("Content-Type: application/json")?;
+    // Send the body without waiting for a "100 Continue"
+    headers.append("Expect:")?;
+    handle.url(url.as_str())?;
+    handle.http_headers(headers)?;
+    handle.post(true)?;
+    handle.post_fields_copy(body)?;
+    handle.connect_timeout(EXPORT_TIMEOUT)?;
+    handle.timeout(EXPORT_TIMEOUT)?;
+
+    let mut response = vec![];
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|data| {
+            response.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    Ok((handle.response_code()?, response))
+}
+
+/// The number of spans an OTLP export `response` reports as rejected, and
+/// why
+fn rejected_spans(response: &[u8]) -> Option<(u64, String)> {
+    let response: Value = serde_json::from_slice(response).ok()?;
+    let partial_success = &response["partialSuccess"];
+    // int64s are strings in the JSON encoding
+    let rejected = &partial_success["rejectedSpans"];
+    let rejected = rejected
+        .as_str()
+        .and_then(|rejected| rejected.parse().ok())
+        .or_else(|| rejected.as_u64())
+        .filter(|rejected| *rejected > 0)?;
+    let message = partial_success["errorMessage"]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned();
+    Some((rejected, message))
+}
+
+/// Export `spans` to the collector at `url`, describing any failure
+fn export(
+    handle: &mut Easy,
+    url: &Url,
+    service_name: &str,
+    spans: &[SpanData],
+) -> Result<(), String> {
+    let body = export_request(service_name, spans).to_string();
+    let (status, response) = post(handle, url, body.as_bytes())
+        .map_err(|e| format!("Could not export {} spans: {}", spans.len(), e))?;
+    if !(200..300).contains(&status) {
+        return Err(format!(
+            "OTLP collector rejected {} spans: {} {}",
+            spans.len(),
+            status,
+            String::from_utf8_lossy(&response)
+        ));
+    }
+    if let Some((rejected, message)) = rejected_spans(&response) {
+        return Err(format!(
+            "OTLP collector rejected {} of {} spans: {}",
+            rejected,
+            spans.len(),
+            message
+        ));
+    }
+    Ok(())
+}
+
+/// Batch spans from `receiver`, exporting them every `EXPORT_INTERVAL` or
+/// `MAX_BATCH` spans
+fn run_exporter(url: Url, service_name: String, receiver: Receiver<SpanData>) {
+    // Reused between exports, keeping the collector connection alive
+    let mut handle = Easy::new();
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut deadline = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let disconnected = match receiver.recv_timeout(timeout) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if batch.len() >= MAX_BATCH || Instant::now() >= deadline || disconnected {
+            if !batch.is_empty() {
+                match export(&mut handle, &url, &service_name, &batch) {
+                    Ok(()) => trace!("Exported {} spans", batch.len()),
+                    Err(e) => warn!("⚠️ {}", e),
+                }
+                batch.clear();
+            }
+            deadline = Instant::now() + EXPORT_INTERVAL;
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// The OTLP/HTTP traces URL of a collector's base `endpoint`
+fn traces_url(endpoint: &str) -> Result<Url, ApiError> {
+    let invalid = |msg: String| -> ApiError {
+        ApiErrorKind::Internal(format!("Invalid tracing.otlp_endpoint: {}", msg)).into()
+    };
+    let mut url = Url::parse(endpoint).map_err(|e| invalid(e.to_string()))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid("only http(s) endpoints are supported".to_owned()));
+    }
+    // Any query (e.g. credentials) is kept
+    let path = format!("{}/v1/traces", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    Ok(url)
+}
+
+/// Start exporting spans, when `settings.enabled`
+pub fn init_tracing(settings: &Tracing) -> Result<(), ApiError> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    let url = traces_url(&settings.otlp_endpoint)?;
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let service_name = settings.service_name.clone();
+    thread::Builder::new()
+        .name("otlp-exporter".to_owned())
+        .spawn(move || run_exporter(url, service_name, receiver))?;
+    *EXPORTER.lock().expect("Tracing exporter poisoned") = Some(sender);
+    SAMPLE_RATIO.store(settings.sample_ratio.to_bits(), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::SystemTime;
+
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), TRACEPARENT);
+
+        let unsampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+        assert!(!SpanContext::from_traceparent(unsampled).unwrap().sampled);
+        // Later versions may have more fields
+        assert!(SpanContext::from_traceparent(&format!("01{}-ab", &TRACEPARENT[2..])).is_some());
+        for invalid in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902bz-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-ab",
+        ] {
+            assert!(
+                SpanContext::from_traceparent(invalid).is_none(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_traces_url() {
+        assert_eq!(
+            traces_url("http://localhost:4318").unwrap().as_str(),
+            "http://localhost:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("https://collector/otlp/?token=abc")
+                .unwrap()
+                .as_str(),
+            "https://collector/otlp/v1/traces?token=abc"
+        );
+        assert!(traces_url("ftp://collector").is_err());
+        assert!(traces_url("collector").is_err());
+    }
+
+    /// A stub collector answering a request per connection with each of
+    /// `responses` (status line and body), returning the request lines and
+    /// bodies received
+    fn stub_collector(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (u16, thread::JoinHandle<Vec<(String, Vec<u8>)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let collector = thread::spawn(move || {
+            let mut requests = vec![];
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let mut header = line.splitn(2, ':');
+                    if header
+                        .next()
+                        .unwrap()
+                        .eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = header.next().unwrap().trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                requests.push((request_line, request_body));
+            }
+            requests
+        });
+        (port, collector)
+    }
+
+    fn span() -> SpanData {
+        let parent = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        SpanData {
+            context: SpanContext {
+                span_id: [1; 8],
+                ..parent
+            },
+            parent_span_id: Some(parent.span_id),
+            name: "GET /1.5/{uid}/info/collections".to_owned(),
+            kind: SpanKind::Server,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: vec![("http.status_code", 200u16.into())],
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let (port, collector) = stub_collector(vec![("200 OK", "{}")]);
+        let url = traces_url(&format!("http://127.0.0.1:{}/?token=abc", port)).unwrap();
+        export(&mut Easy::new(), &url, "test", &[span()]).unwrap();
+
+        let requests = collector.join().unwrap();
+        let (request_line, body) = &requests[0];
+        assert_eq!(request_line, "POST /v1/traces?token=abc HTTP/1.1\r\n");
+        let body: Value = serde_json::from_slice(body).unwrap();
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            "test"
+        );
+        let span = &resource_spans["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], "0101010101010101");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["name"], "GET /1.5/{uid}/info/collections");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["attributes"][0]["key"], "http.status_code");
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "200");
+    }
+
+    #[test]
+    fn test_export_failures() {
+        let (port, collector) = stub_collector(vec![
+            ("400 Bad Request", r#"{"message": "invalid span"}"#),
+            (
+                "200 OK",
+                r#"{"partialSuccess": {"rejectedSpans": "1", "errorMessage": "too old"}}"#,
+            ),
+        ]);
+        let url = traces_url(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let mut handle = Easy::new();
+        let error = export(&mut handle, &url, "test", &[span()]).unwrap_err();
+        assert!(error.contains("400"), "{}", error);
+        assert!(error.contains("invalid span"), "{}", error);
+        let error = export(&mut handle, &url, "test", &[span()]).unwrap_err();
+        assert_eq!(error, "OTLP collector rejected 1 of 1 spans: too old");
+        assert_eq!(collector.join().unwrap().len(), 2);
+
+        // Nothing's listening anymore
+        assert!(export(&mut handle, &url, "test", &[span()]).is_err());
+    }
+}
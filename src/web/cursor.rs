@@ -0,0 +1,200 @@
+//! Opaque, signed pagination cursors.
+//!
+//! The `X-Weave-Next-Offset` handed to clients wraps the db's internal
+//! `Offset` along with the user, collection and parameters of the query that
+//! produced it, signed with a key derived from the master secret. Clients may
+//! only echo it back unaltered, alongside the same query.
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::extractors::{BsoQueryParams, Offset};
+use crate::db::Sorting;
+use crate::error::ApiResult;
+
+/// Prefix identifying the current cursor format
+const CURSOR_VERSION: &str = "v1";
+
+/// The signed contents of a cursor
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct CursorPayload {
+    /// The db's internal offset
+    #[serde(rename = "o")]
+    offset: String,
+    #[serde(rename = "u")]
+    uid: u64,
+    #[serde(rename = "c")]
+    collection: String,
+    #[serde(rename = "s")]
+    sort: Sorting,
+    #[serde(rename = "n")]
+    newer: Option<i64>,
+    #[serde(rename = "b")]
+    older: Option<i64>,
+    #[serde(rename = "l")]
+    limit: Option<u32>,
+    /// A digest of the ids (up to `BATCH_MAX_IDS` of them), keeping the
+    /// cursor short
+    #[serde(rename = "i")]
+    ids: String,
+    #[serde(rename = "f")]
+    full: bool,
+}
+
+impl CursorPayload {
+    fn new(offset: String, uid: u64, collection: &str, query: &BsoQueryParams) -> Self {
+        let mut ids = Sha256::new();
+        for id in &query.ids {
+            ids.input(id.as_bytes());
+            ids.input(b",");
+        }
+        CursorPayload {
+            offset,
+            uid,
+            collection: collection.to_owned(),
+            sort: query.sort,
+            newer: query.newer.map(|ts| ts.as_i64()),
+            older: query.older.map(|ts| ts.as_i64()),
+            limit: query.limit,
+            ids: base64::encode_config(&ids.result(), base64::URL_SAFE_NO_PAD),
+            full: query.full,
+        }
+    }
+}
+
+fn hmac(key: &[u8], version: &str, payload: &str) -> ApiResult<Hmac<Sha256>> {
+    let mut hmac: Hmac<Sha256> = Hmac::new_varkey(key)?;
+    hmac.input(version.as_bytes());
+    hmac.input(b".");
+    hmac.input(payload.as_bytes());
+    Ok(hmac)
+}
+
+/// Sign the db's internal `offset` for the query (of `uid`'s `collection`)
+/// that produced it
+pub fn sign(
+    key: &[u8],
+    offset: String,
+    uid: u64,
+    collection: &str,
+    query: &BsoQueryParams,
+) -> ApiResult<String> {
+    let payload = serde_json::to_vec(&CursorPayload::new(offset, uid, collection, query))?;
+    let payload = base64::encode_config(&payload, base64::URL_SAFE_NO_PAD);
+    let signature = hmac(key, CURSOR_VERSION, &payload)?.result().code();
+    Ok(format!(
+        "{}.{}.{}",
+        CURSOR_VERSION,
+        payload,
+        base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Verify a cursor returned by a client, returning the db's internal `Offset`
+///
+/// Fails if the cursor was altered or was produced by a query of another user
+/// or collection, or with different parameters than `query`.
+pub fn verify(
+    key: &[u8],
+    cursor: &str,
+    uid: u64,
+    collection: &str,
+    query: &BsoQueryParams,
+) -> Result<Offset, String> {
+    let mut parts = cursor.splitn(3, '.');
+    let (version, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(version), Some(payload), Some(signature)) => (version, payload, signature),
+        _ => return Err("Malformed offset".to_owned()),
+    };
+    if version != CURSOR_VERSION {
+        return Err("Unsupported offset version".to_owned());
+    }
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "Malformed offset".to_owned())?;
+    hmac(key, version, payload)
+        .map_err(|e| e.to_string())?
+        .verify(&signature)
+        .map_err(|_| "Invalid offset signature".to_owned())?;
+
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "Malformed offset".to_owned())?;
+    let payload: CursorPayload =
+        serde_json::from_slice(&payload).map_err(|_| "Malformed offset".to_owned())?;
+    let expected = CursorPayload::new(payload.offset.clone(), uid, collection, query);
+    if payload != expected {
+        return Err("Offset does not match query".to_owned());
+    }
+    Offset::from_str(&payload.offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, verify};
+    use crate::db::{util::SyncTimestamp, Sorting};
+    use crate::web::extractors::BsoQueryParams;
+
+    const KEY: &[u8] = b"cursor key";
+
+    fn query(sort: Sorting, newer: u64) -> BsoQueryParams {
+        BsoQueryParams {
+            sort,
+            newer: Some(SyncTimestamp::from_milliseconds(newer)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let query = query(Sorting::Newest, 1000);
+        let cursor = sign(KEY, "1500:Ym8x".to_owned(), 1, "tabs", &query).unwrap();
+        assert!(cursor.starts_with("v1."));
+        assert!(!cursor.contains("1500"));
+        let offset = verify(KEY, &cursor, 1, "tabs", &query).unwrap();
+        assert_eq!(offset.to_string(), "1500:Ym8x");
+        assert_eq!(offset.keyset().unwrap().1, "bo1");
+    }
+
+    #[test]
+    fn tampered() {
+        let query = query(Sorting::Index, 0);
+        let cursor = sign(KEY, "10".to_owned(), 1, "tabs", &query).unwrap();
+        let forged = sign(b"another key", "20".to_owned(), 1, "tabs", &query).unwrap();
+        let (forged_payload, _) = forged.split_at(forged.rfind('.').unwrap());
+        let (_, signature) = cursor.split_at(cursor.rfind('.').unwrap());
+        let forged = format!("{}{}", forged_payload, signature);
+        assert!(verify(KEY, &forged, 1, "tabs", &query).is_err());
+        assert!(verify(KEY, &cursor, 1, "tabs", &query).is_ok());
+        assert!(verify(b"another key", &cursor, 1, "tabs", &query).is_err());
+        let version = cursor.replacen("v1", "v2", 1);
+        assert!(verify(KEY, &version, 1, "tabs", &query).is_err());
+        assert!(verify(KEY, "10", 1, "tabs", &query).is_err());
+    }
+
+    #[test]
+    fn mismatched_query() {
+        let index = query(Sorting::Index, 0);
+        let cursor = sign(KEY, "10".to_owned(), 1, "tabs", &index).unwrap();
+        assert!(verify(KEY, &cursor, 1, "tabs", &query(Sorting::Oldest, 0)).is_err());
+        assert!(verify(KEY, &cursor, 1, "tabs", &query(Sorting::Index, 5)).is_err());
+        assert!(verify(KEY, &cursor, 2, "tabs", &index).is_err());
+        assert!(verify(KEY, &cursor, 1, "history", &index).is_err());
+        for other in &[
+            BsoQueryParams {
+                limit: Some(5),
+                ..query(Sorting::Index, 0)
+            },
+            BsoQueryParams {
+                ids: vec!["a".to_owned()],
+                ..query(Sorting::Index, 0)
+            },
+            BsoQueryParams {
+                full: true,
+                ..query(Sorting::Index, 0)
+            },
+        ] {
+            assert!(verify(KEY, &cursor, 1, "tabs", other).is_err());
+        }
+    }
+}
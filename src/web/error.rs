@@ -118,7 +118,7 @@ impl From<Context<ValidationErrorKind>> for ValidationError {
             {
                 match name.to_ascii_lowercase().as_str() {
                     "accept" => StatusCode::NOT_ACCEPTABLE,
-                    "content-type" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "content-type" | "content-encoding" => StatusCode::UNSUPPORTED_MEDIA_TYPE,
                     _ => StatusCode::BAD_REQUEST,
                 }
             }
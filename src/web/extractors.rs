@@ -2,19 +2,29 @@
 //!
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
-use std::{self, collections::HashMap, num::ParseIntError, str::FromStr};
+use std::{
+    self,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    mem,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use actix_web::{
     dev::{ConnectionInfo, Extensions, Payload, RequestHead},
     http::{
-        header::{qitem, Accept, ContentType, Header, HeaderMap},
+        header::{qitem, Accept, ContentType, Header, HeaderMap, CONTENT_ENCODING},
         Uri,
     },
-    web::{Data, Json, Query},
+    web::{Data, Query},
     Error, FromRequest, HttpMessage, HttpRequest,
 };
 
+use bytes::{Bytes, BytesMut};
+use flate2::write::GzDecoder;
 use futures::future::{self, FutureExt, LocalBoxFuture, Ready, TryFutureExt};
+use futures::StreamExt;
 
 use lazy_static::lazy_static;
 use mime::STAR_STAR;
@@ -28,12 +38,16 @@ use validator::{Validate, ValidationError};
 
 use crate::db::transaction::DbTransactionPool;
 use crate::db::{util::SyncTimestamp, DbPool, Sorting};
-use crate::error::ApiError;
-use crate::server::{metrics, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX};
+use crate::error::{ApiError, ApiErrorKind};
+use crate::server::{
+    health::Health, metrics, reload::Reloader, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX,
+};
 use crate::settings::{Secrets, ServerLimits};
 use crate::web::{
     auth::HawkPayload,
+    cursor,
     error::{HawkErrorKind, ValidationErrorKind},
+    middleware::access_log::RequestStats,
     tags::Tags,
     X_WEAVE_RECORDS,
 };
@@ -133,6 +147,150 @@ fn get_accepted(req: &HttpRequest, accepted: &[&str], default: &'static str) ->
     "invalid".to_string()
 }
 
+/// Size of the slices of a gzip body inflated at a time
+const GZIP_SLICE_BYTES: usize = 4 * 1024;
+
+/// Whether a value exceeds an optional limit
+fn exceeds(value: Option<u32>, limit: Option<u32>) -> bool {
+    match (value, limit) {
+        (Some(value), Some(limit)) => value > limit,
+        _ => false,
+    }
+}
+
+fn payload_error<E: std::fmt::Debug>(e: E) -> Error {
+    warn!("⚠️ Payload read error: {:?}", e);
+    ValidationErrorKind::FromDetails(
+        "Mimetype/encoding/content-length error".to_owned(),
+        RequestErrorLocation::Header,
+        None,
+        None,
+    )
+    .into()
+}
+
+/// Decodes a request body's `Content-Encoding` as it arrives.
+///
+/// `max_request_bytes` is enforced against both the encoded and the decoded
+/// body, so a small compressed body can't inflate past it. The decoded BSOs
+/// are then subject to the usual `max_post_bytes` checks.
+struct BodyDecoder {
+    gzip: Option<GzDecoder<Vec<u8>>>,
+    max_request_bytes: usize,
+    encoded_bytes: usize,
+    decoded_bytes: usize,
+}
+
+impl BodyDecoder {
+    fn new(req: &HttpRequest, max_request_bytes: usize) -> Result<Self, Error> {
+        let encoding = match req.headers().get(CONTENT_ENCODING) {
+            Some(value) => value
+                .to_str()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase(),
+            None => "identity".to_owned(),
+        };
+        let gzip = match encoding.as_str() {
+            "identity" => None,
+            "gzip" | "x-gzip" => Some(GzDecoder::new(Vec::new())),
+            _ => {
+                return Err(ValidationErrorKind::FromDetails(
+                    format!("Unsupported Content-Encoding {:?}", encoding),
+                    RequestErrorLocation::Header,
+                    Some("Content-Encoding".to_owned()),
+                    None,
+                )
+                .into());
+            }
+        };
+        Ok(BodyDecoder {
+            gzip,
+            max_request_bytes,
+            encoded_bytes: 0,
+            decoded_bytes: 0,
+        })
+    }
+
+    fn too_large() -> Error {
+        ValidationErrorKind::FromDetails(
+            "size-limit-exceeded".to_owned(),
+            RequestErrorLocation::Body,
+            None,
+            None,
+        )
+        .into()
+    }
+
+    fn invalid_gzip(e: std::io::Error) -> Error {
+        warn!("⚠️ Could not decode gzip request body: {:?}", e);
+        ValidationErrorKind::FromDetails(
+            "Invalid gzip request body".to_owned(),
+            RequestErrorLocation::Body,
+            None,
+            None,
+        )
+        .into()
+    }
+
+    fn add_decoded(&mut self, len: usize) -> Result<(), Error> {
+        self.decoded_bytes += len;
+        if self.decoded_bytes > self.max_request_bytes {
+            return Err(Self::too_large());
+        }
+        Ok(())
+    }
+
+    /// Decode the next chunk of the body
+    fn decode(&mut self, chunk: Bytes) -> Result<Bytes, Error> {
+        self.encoded_bytes += chunk.len();
+        if self.encoded_bytes > self.max_request_bytes {
+            return Err(Self::too_large());
+        }
+        let mut decoder = match self.gzip.take() {
+            Some(decoder) => decoder,
+            None => return Ok(chunk),
+        };
+        // Inflate a slice at a time, so a single chunk can't expand far past
+        // the limit before it's checked
+        let mut decoded = BytesMut::new();
+        for slice in chunk.chunks(GZIP_SLICE_BYTES) {
+            decoder.write_all(slice).map_err(Self::invalid_gzip)?;
+            let output = mem::take(decoder.get_mut());
+            self.add_decoded(output.len())?;
+            decoded.extend_from_slice(&output);
+        }
+        self.gzip = Some(decoder);
+        Ok(decoded.freeze())
+    }
+
+    /// Decode whatever remains once the body has been read
+    fn finish(&mut self) -> Result<Bytes, Error> {
+        let decoder = match self.gzip.take() {
+            Some(decoder) => decoder,
+            None => return Ok(Bytes::new()),
+        };
+        let output = decoder.finish().map_err(Self::invalid_gzip)?;
+        self.add_decoded(output.len())?;
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Read the request body, decoding a `Content-Encoding: gzip` body.
+async fn read_body(
+    req: HttpRequest,
+    mut payload: Payload,
+    max_request_bytes: usize,
+) -> Result<Bytes, Error> {
+    let mut decoder = BodyDecoder::new(&req, max_request_bytes)?;
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&decoder.decode(chunk.map_err(payload_error)?)?);
+    }
+    body.extend_from_slice(&decoder.finish()?);
+    Ok(body.freeze())
+}
+
 #[derive(Clone, Default, Deserialize)]
 pub struct BsoBodies {
     pub valid: Vec<BatchBsoBody>,
@@ -152,6 +310,9 @@ impl FromRequest for BsoBodies {
     ///   - Request content-type is a valid value
     ///   - Valid BSO's include a BSO id
     ///
+    /// BSO's are parsed and validated as the body arrives, so a malformed
+    /// record fails the request without reading the remainder.
+    ///
     /// No collection id is used, so payload checks are not done here.
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         // Only try and parse the body if its a valid content-type
@@ -184,29 +345,6 @@ impl FromRequest for BsoBodies {
             ));
         }
 
-        // Load the entire request into a String
-        let fut = <String>::from_request(req, payload).map_err(|e| {
-            warn!("⚠️ Payload read error: {:?}", e);
-            ValidationErrorKind::FromDetails(
-                "Mimetype/encoding/content-length error".to_owned(),
-                RequestErrorLocation::Header,
-                None,
-                None,
-            )
-            .into()
-        });
-
-        // Avoid duplicating by defining our error func now, doesn't need the box wrapper
-        fn make_error(tags: Option<Tags>) -> Error {
-            ValidationErrorKind::FromDetails(
-                "Invalid JSON in request body".to_owned(),
-                RequestErrorLocation::Body,
-                Some("bsos".to_owned()),
-                tags,
-            )
-            .into()
-        }
-
         // Define a new bool to check from a static closure to release the reference on the
         // content_type header
         let newlines: bool = content_type == "application/newlines";
@@ -227,102 +365,279 @@ impl FromRequest for BsoBodies {
                 ));
             }
         };
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
-        let max_post_bytes = state.limits.max_post_bytes as usize;
-
-        let fut = fut.and_then(move |body| {
-            // Get all the raw / values
-            let bsos: Vec<Value> = if newlines {
-                let mut bsos = Vec::new();
-                for item in body.lines() {
-                    // Check that its a valid JSON map like we expect
-                    if let Ok(raw_json) = serde_json::from_str::<Value>(&item) {
-                        bsos.push(raw_json);
-                    } else {
-                        // Per Python version, BSO's must json deserialize
-                        return future::err(make_error(None));
-                    }
-                }
-                bsos
-            } else if let Ok(json_vals) = serde_json::from_str::<Vec<Value>>(&body) {
-                json_vals
-            } else {
-                // Per Python version, BSO's must json deserialize
-                return future::err(make_error(None));
+        let mut decoder =
+            match BodyDecoder::new(req, state.limits.load().max_request_bytes as usize) {
+                Ok(decoder) => decoder,
+                Err(e) => return Box::pin(future::err(e)),
             };
+        let limits = state
+            .limits
+            .load()
+            .for_collection(req.match_info().get("collection").unwrap_or_default());
+        let mut parser = BsoBodiesParser::new(
+            newlines,
+            limits.max_record_payload_bytes as usize,
+            limits.max_post_bytes as usize,
+            limits.max_ttl,
+        );
+        let mut payload = payload.take();
+
+        Box::pin(async move {
+            while let Some(chunk) = payload.next().await {
+                parser.feed(&decoder.decode(chunk.map_err(payload_error)?)?)?;
+            }
+            parser.feed(&decoder.finish()?)?;
+            parser.finish()
+        })
+    }
+}
+
+/// Progress through a JSON array of BSO's
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArrayState {
+    /// Expecting the opening `[`
+    Start,
+    /// Expecting the first BSO or the closing `]`
+    First,
+    /// Expecting a BSO
+    Value,
+    /// Within a BSO
+    InValue,
+    /// Expecting a `,` or the closing `]`
+    Next,
+    /// Past the closing `]`
+    End,
+}
 
-            // Validate all the BSO's, move invalid to our other list. Assume they'll all make
-            // it with our pre-allocation
-            let mut valid: Vec<BatchBsoBody> = Vec::with_capacity(bsos.len());
+/// Incrementally parses and validates the BSO's of a POST body, either
+/// newline delimited or a JSON array, as the body arrives.
+///
+/// Only the record currently being received is buffered: each BSO is
+/// validated as soon as it's complete.
+struct BsoBodiesParser {
+    newlines: bool,
+    max_payload_size: usize,
+    max_post_bytes: usize,
+    max_ttl: Option<u32>,
+    /// Received data not yet parsed into a BSO
+    buf: Vec<u8>,
+    /// How far into `buf` has been scanned
+    scanned: usize,
+    state: ArrayState,
+    /// Nesting depth within the current BSO
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Keep track of our total payload size
+    total_payload_size: usize,
+    /// Track the bso id's for dupe detection
+    bso_ids: HashSet<String>,
+    bodies: BsoBodies,
+}
 
-            // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
-            // per the way the Python version works.
-            let mut invalid: HashMap<String, String> = HashMap::new();
+impl BsoBodiesParser {
+    fn new(
+        newlines: bool,
+        max_payload_size: usize,
+        max_post_bytes: usize,
+        max_ttl: Option<u32>,
+    ) -> Self {
+        BsoBodiesParser {
+            newlines,
+            max_payload_size,
+            max_post_bytes,
+            max_ttl,
+            buf: Vec::new(),
+            scanned: 0,
+            state: ArrayState::Start,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            total_payload_size: 0,
+            bso_ids: HashSet::new(),
+            bodies: BsoBodies::default(),
+        }
+    }
 
-            // Keep track of our total payload size
-            let mut total_payload_size = 0;
+    fn invalid_json() -> Error {
+        ValidationErrorKind::FromDetails(
+            "Invalid JSON in request body".to_owned(),
+            RequestErrorLocation::Body,
+            Some("bsos".to_owned()),
+            None,
+        )
+        .into()
+    }
+
+    /// Parse the next chunk of the (decoded) body
+    fn feed(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(data);
+        let buf = mem::take(&mut self.buf);
+        let consumed = if self.newlines {
+            self.parse_lines(&buf)
+        } else {
+            self.parse_array(&buf)
+        }?;
+        self.buf = buf;
+        self.buf.drain(..consumed);
+        self.scanned = self.buf.len();
+        Ok(())
+    }
 
-            // Temporarily track the bso id's for dupe detection
-            let mut bso_ids: Vec<String> = Vec::with_capacity(bsos.len());
+    /// Finish parsing once the entire body has been received
+    fn finish(mut self) -> Result<BsoBodies, Error> {
+        if self.newlines {
+            // The final line needn't be terminated
+            if !self.buf.is_empty() {
+                let line = mem::take(&mut self.buf);
+                self.parse_line(&line)?;
+            }
+        } else if self.state != ArrayState::End {
+            return Err(Self::invalid_json());
+        }
+        Ok(self.bodies)
+    }
 
-            for bso in bsos {
-                // Error out if its not a JSON mapping type
-                if !bso.is_object() {
-                    return future::err(make_error(None));
-                }
-                // Save all id's we get, check for missing id, or duplicate.
-                let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
-                    let id = id.to_string();
-                    if bso_ids.contains(&id) {
-                        return future::err(
-                            ValidationErrorKind::FromDetails(
-                                "Input BSO has duplicate ID".to_owned(),
-                                RequestErrorLocation::Body,
-                                Some("bsos".to_owned()),
-                                None,
-                            )
-                            .into(),
-                        );
-                    } else {
-                        bso_ids.push(id.clone());
-                        id
+    /// Parse each complete line of `buf`, returning how much was consumed
+    fn parse_lines(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut start = 0;
+        for (i, byte) in buf.iter().enumerate().skip(self.scanned) {
+            if *byte == b'\n' {
+                self.parse_line(&buf[start..i])?;
+                start = i + 1;
+            }
+        }
+        Ok(start)
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let line = if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        let line = std::str::from_utf8(line).map_err(payload_error)?;
+        // Per Python version, BSO's must json deserialize
+        let bso = serde_json::from_str::<Value>(line).map_err(|_| Self::invalid_json())?;
+        self.add(bso)
+    }
+
+    /// Parse each complete BSO of `buf`, returning how much was consumed
+    fn parse_array(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // Start of the BSO currently being received
+        let mut start = if self.state == ArrayState::InValue {
+            0
+        } else {
+            self.scanned
+        };
+        for (i, byte) in buf.iter().enumerate().skip(self.scanned) {
+            if self.state == ArrayState::InValue {
+                if self.in_string {
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if *byte == b'\\' {
+                        self.escaped = true;
+                    } else if *byte == b'"' {
+                        self.in_string = false;
                     }
-                } else {
-                    return future::err(
-                        ValidationErrorKind::FromDetails(
-                            "Input BSO has no ID".to_owned(),
-                            RequestErrorLocation::Body,
-                            Some("bsos".to_owned()),
-                            None,
-                        )
-                        .into(),
-                    );
-                };
-                match BatchBsoBody::from_raw_bso(&bso) {
-                    Ok(b) => {
-                        // Is this record too large? Deny if it is.
-                        let payload_size = b
-                            .payload
-                            .as_ref()
-                            .map(std::string::String::len)
-                            .unwrap_or_default();
-                        total_payload_size += payload_size;
-                        if payload_size <= max_payload_size && total_payload_size <= max_post_bytes
-                        {
-                            valid.push(b);
-                        } else {
-                            invalid.insert(b.id, "retry bytes".to_string());
+                    continue;
+                }
+                match *byte {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' => {
+                        self.depth -= 1;
+                        if self.depth == 0 {
+                            let raw =
+                                std::str::from_utf8(&buf[start..=i]).map_err(payload_error)?;
+                            let bso = serde_json::from_str::<Value>(raw)
+                                .map_err(|_| Self::invalid_json())?;
+                            self.add(bso)?;
+                            self.state = ArrayState::Next;
+                            start = i + 1;
                         }
                     }
-                    Err(e) => {
-                        invalid.insert(bso_id, e);
-                    }
+                    _ => (),
                 }
+                continue;
             }
-            future::ok(BsoBodies { valid, invalid })
-        });
+            start = i + 1;
+            self.state = match (self.state, *byte) {
+                (_, b' ') | (_, b'\t') | (_, b'\n') | (_, b'\r') => continue,
+                (ArrayState::Start, b'[') => ArrayState::First,
+                (ArrayState::First, b']') | (ArrayState::Next, b']') => ArrayState::End,
+                (ArrayState::First, b'{') | (ArrayState::Value, b'{') => {
+                    start = i;
+                    self.depth = 1;
+                    ArrayState::InValue
+                }
+                (ArrayState::Next, b',') => ArrayState::Value,
+                _ => return Err(Self::invalid_json()),
+            };
+        }
+        Ok(start)
+    }
 
-        Box::pin(fut)
+    /// Validate a BSO, moving it to the invalid list if it doesn't pass
+    fn add(&mut self, bso: Value) -> Result<(), Error> {
+        // Error out if its not a JSON mapping type
+        if !bso.is_object() {
+            return Err(Self::invalid_json());
+        }
+        // Save all id's we get, check for missing id, or duplicate.
+        let bso_id = if let Some(id) = bso.get("id").and_then(serde_json::Value::as_str) {
+            let id = id.to_string();
+            if !self.bso_ids.insert(id.clone()) {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Input BSO has duplicate ID".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bsos".to_owned()),
+                    None,
+                )
+                .into());
+            }
+            id
+        } else {
+            return Err(ValidationErrorKind::FromDetails(
+                "Input BSO has no ID".to_owned(),
+                RequestErrorLocation::Body,
+                Some("bsos".to_owned()),
+                None,
+            )
+            .into());
+        };
+        // Invalid BSO's are any BSO that can deserialize despite how wrong the contents are
+        // per the way the Python version works.
+        match BatchBsoBody::from_raw_bso(&bso) {
+            Ok(ref b) if exceeds(b.ttl, self.max_ttl) => {
+                self.bodies
+                    .invalid
+                    .insert(bso_id, "invalid ttl".to_string());
+            }
+            Ok(b) => {
+                // Is this record too large? Deny if it is.
+                let payload_size = b
+                    .payload
+                    .as_ref()
+                    .map(std::string::String::len)
+                    .unwrap_or_default();
+                self.total_payload_size += payload_size;
+                if payload_size <= self.max_payload_size
+                    && self.total_payload_size <= self.max_post_bytes
+                {
+                    self.bodies.valid.push(b);
+                } else {
+                    self.bodies.invalid.insert(b.id, "retry bytes".to_string());
+                }
+            }
+            Err(e) => {
+                self.bodies.invalid.insert(bso_id, e);
+            }
+        }
+        Ok(())
     }
 }
 
@@ -392,10 +707,20 @@ impl FromRequest for BsoBody {
             }
         };
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
-
-        let fut = <Json<BsoBody>>::from_request(&req, payload)
-            .map_err(|e| {
+        let limits = state
+            .limits
+            .load()
+            .for_collection(req.match_info().get("collection").unwrap_or_default());
+        let max_payload_size = limits.max_record_payload_bytes as usize;
+        let max_ttl = limits.max_ttl;
+
+        let fut = read_body(
+            req.clone(),
+            payload.take(),
+            state.limits.load().max_request_bytes as usize,
+        )
+        .and_then(|body| {
+            future::ready(serde_json::from_slice::<BsoBody>(&body).map_err(|e| {
                 warn!("⚠️ Could not parse BSO Body: {:?}", e);
                 let err: ApiError = ValidationErrorKind::FromDetails(
                     e.to_string(),
@@ -405,36 +730,44 @@ impl FromRequest for BsoBody {
                 )
                 .into();
                 err.into()
-            })
-            .and_then(move |bso: Json<BsoBody>| {
-                // Check the max payload size manually with our desired limit
-                if bso
-                    .payload
-                    .as_ref()
-                    .map(std::string::String::len)
-                    .unwrap_or_default()
-                    > max_payload_size
-                {
-                    let err: ApiError = ValidationErrorKind::FromDetails(
-                        "payload too large".to_owned(),
-                        RequestErrorLocation::Body,
-                        Some("bso".to_owned()),
-                        None,
-                    )
-                    .into();
-                    return future::err(err.into());
-                }
-                if let Err(e) = bso.validate() {
-                    let err: ApiError = ValidationErrorKind::FromValidationErrors(
-                        e,
-                        RequestErrorLocation::Body,
-                        None,
-                    )
-                    .into();
-                    return future::err(err.into());
-                }
-                future::ok(bso.into_inner())
-            });
+            }))
+        })
+        .and_then(move |bso: BsoBody| {
+            // Check the max payload size manually with our desired limit
+            if bso
+                .payload
+                .as_ref()
+                .map(std::string::String::len)
+                .unwrap_or_default()
+                > max_payload_size
+            {
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    "payload too large".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bso".to_owned()),
+                    None,
+                )
+                .into();
+                return future::err(err.into());
+            }
+            if let Err(e) = bso.validate() {
+                let err: ApiError =
+                    ValidationErrorKind::FromValidationErrors(e, RequestErrorLocation::Body, None)
+                        .into();
+                return future::err(err.into());
+            }
+            if exceeds(bso.ttl, max_ttl) {
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    "Invalid TTL".to_owned(),
+                    RequestErrorLocation::Body,
+                    Some("bso".to_owned()),
+                    None,
+                )
+                .into();
+                return future::err(err.into());
+            }
+            future::ok(bso)
+        });
 
         Box::pin(fut)
     }
@@ -655,6 +988,8 @@ pub struct CollectionRequest {
     pub user_id: HawkIdentifier,
     pub query: BsoQueryParams,
     pub reply: ReplyFormat,
+    /// Key for signing the next offset's cursor
+    pub cursor_secret: [u8; 32],
     pub metrics: metrics::Metrics,
     pub tags: Option<Tags>,
 }
@@ -696,11 +1031,26 @@ impl FromRequest for CollectionRequest {
                 }
             };
 
+            let cursor_secret = match req.app_data::<Data<ServerState>>() {
+                Some(state) => state.secrets.cursor_secret,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        Some(tags),
+                    )
+                    .into());
+                }
+            };
+
             Ok(CollectionRequest {
                 collection,
                 user_id,
                 query,
                 reply,
+                cursor_secret,
                 metrics: metrics::Metrics::from(&req),
                 tags: Some(tags),
             })
@@ -754,10 +1104,15 @@ impl FromRequest for CollectionPostRequest {
                 }
             };
 
-            let max_post_records = i64::from(state.limits.max_post_records);
-
             let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
             let collection = CollectionParam::from_request(&req, &mut payload).await?;
+            let max_post_records = i64::from(
+                state
+                    .limits
+                    .load()
+                    .for_collection(&collection.collection)
+                    .max_post_records,
+            );
             let query = BsoQueryParams::from_request(&req, &mut payload).await?;
             let mut bsos = BsoBodies::from_request(&req, &mut payload).await?;
 
@@ -789,6 +1144,10 @@ impl FromRequest for CollectionPostRequest {
                 }
             }
 
+            if let Some(stats) = RequestStats::get(&req) {
+                stats.add_records(bsos.valid.len() + bsos.invalid.len());
+            }
+
             // XXX: let's not use extract here (maybe convert to extrude?)
             let batch = BatchRequestOpt::extract(&req).await?;
             Ok(CollectionPostRequest {
@@ -887,16 +1246,189 @@ impl FromRequest for BsoPutRequest {
                     }
                 }
             }
+            if let Some(stats) = RequestStats::get(&req) {
+                stats.add_records(1);
+            }
             Ok(BsoPutRequest {
                 collection,
                 user_id,
-                query,
-                bso: bso.bso,
-                body,
-                metrics,
+                query,
+                bso: bso.bso,
+                body,
+                metrics,
+            })
+        }
+        .boxed_local()
+    }
+}
+
+/// The changes to apply to a single collection within a storage transaction
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCollectionWrites {
+    #[serde(default)]
+    put: Vec<Value>,
+    #[serde(default)]
+    delete: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CollectionWrites {
+    pub put: Vec<BatchBsoBody>,
+    pub delete: Vec<String>,
+}
+
+/// Storage Transaction Request extractor
+///
+/// Extracts/validates the puts and deletes (keyed by collection name) of a
+/// request atomically writing to multiple collections.
+pub struct StorageTransactionRequest {
+    pub user_id: HawkIdentifier,
+    pub collections: BTreeMap<String, CollectionWrites>,
+    pub metrics: metrics::Metrics,
+}
+
+impl StorageTransactionRequest {
+    fn validate_writes(
+        raw: BTreeMap<String, RawCollectionWrites>,
+        limits: &ServerLimits,
+    ) -> Result<BTreeMap<String, CollectionWrites>, String> {
+        if raw.is_empty() {
+            return Err("No collections specified".to_owned());
+        }
+        let mut total_records = 0;
+        let mut total_bytes = 0;
+        let mut collections = BTreeMap::new();
+        for (collection, raw_writes) in raw {
+            if !VALID_COLLECTION_ID_REGEX.is_match(&collection) {
+                return Err(format!("Invalid collection: {}", collection));
+            }
+            if raw_writes.put.is_empty() && raw_writes.delete.is_empty() {
+                return Err(format!("No writes to {}", collection));
+            }
+            if raw_writes.delete.len() > BATCH_MAX_IDS {
+                return Err(format!("Too many ids to delete in {}", collection));
+            }
+            let collection_limits = limits.for_collection(&collection);
+            let mut writes = CollectionWrites::default();
+            let mut collection_bytes = 0;
+            for id in raw_writes.delete {
+                if !VALID_ID_REGEX.is_match(&id) || writes.delete.contains(&id) {
+                    return Err(format!("Invalid id to delete in {}: {}", collection, id));
+                }
+                writes.delete.push(id);
+            }
+            for raw_bso in raw_writes.put {
+                let bso = BatchBsoBody::from_raw_bso(&raw_bso)?;
+                if writes.delete.contains(&bso.id) || writes.put.iter().any(|b| b.id == bso.id) {
+                    return Err(format!("Input BSO has duplicate ID: {}", bso.id));
+                }
+                let payload_size = bso.payload.as_ref().map(String::len).unwrap_or_default();
+                if payload_size > collection_limits.max_record_payload_bytes as usize {
+                    return Err("size-limit-exceeded".to_owned());
+                }
+                if exceeds(bso.ttl, collection_limits.max_ttl) {
+                    return Err(format!("Invalid TTL in {}: {}", collection, bso.id));
+                }
+                if collection == "crypto"
+                    && bso
+                        .payload
+                        .as_ref()
+                        .map_or(false, |data| KNOWN_BAD_PAYLOAD_REGEX.is_match(data))
+                {
+                    return Err("Known-bad BSO payload".to_owned());
+                }
+                collection_bytes += payload_size;
+                writes.put.push(bso);
+            }
+            // Each collection's writes are limited like a POST to it
+            if writes.put.len() > collection_limits.max_post_records as usize
+                || collection_bytes > collection_limits.max_post_bytes as usize
+            {
+                return Err("size-limit-exceeded".to_owned());
+            }
+            total_records += writes.put.len();
+            total_bytes += collection_bytes;
+            collections.insert(collection, writes);
+        }
+        if total_records > limits.max_post_records as usize
+            || total_bytes > limits.max_post_bytes as usize
+        {
+            return Err("size-limit-exceeded".to_owned());
+        }
+        Ok(collections)
+    }
+}
+
+impl FromRequest for StorageTransactionRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = payload.take();
+        Box::pin(async move {
+            let tags = Tags::from_request(&req, &mut payload).await?;
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("app_data".to_owned()),
+                        Some(tags),
+                    )
+                    .into());
+                }
+            };
+            let user_id = HawkIdentifier::from_request(&req, &mut payload).await?;
+            let body = read_body(
+                req.clone(),
+                payload,
+                state.limits.load().max_request_bytes as usize,
+            )
+            .await?;
+            let raw = serde_json::from_slice::<BTreeMap<String, RawCollectionWrites>>(&body)
+                .map_err(|e| {
+                    warn!("⚠️ Could not parse storage transaction: {:?}", e);
+                    let err: ApiError = ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::Body,
+                        Some("bsos".to_owned()),
+                        Some(tags.clone()),
+                    )
+                    .into();
+                    err
+                })?;
+            let collections =
+                Self::validate_writes(raw, &state.limits.load()).map_err(|description| {
+                    let err: ApiError = ValidationErrorKind::FromDetails(
+                        description,
+                        RequestErrorLocation::Body,
+                        Some("bsos".to_owned()),
+                        Some(tags.clone()),
+                    )
+                    .into();
+                    err
+                })?;
+
+            if let Some(stats) = RequestStats::get(&req) {
+                stats.add_records(
+                    collections
+                        .values()
+                        .map(|writes| writes.put.len() + writes.delete.len())
+                        .sum(),
+                );
+            }
+
+            Ok(StorageTransactionRequest {
+                user_id,
+                collections,
+                metrics: metrics::Metrics::from(&req),
             })
-        }
-        .boxed_local()
+        })
     }
 }
 
@@ -935,16 +1467,8 @@ impl FromRequest for ConfigRequest {
             }
         };
 
-        let data = &state.limits;
         Box::pin(future::ok(Self {
-            limits: ServerLimits {
-                max_post_bytes: data.max_post_bytes,
-                max_post_records: data.max_post_records,
-                max_record_payload_bytes: data.max_record_payload_bytes,
-                max_request_bytes: data.max_request_bytes,
-                max_total_bytes: data.max_total_bytes,
-                max_total_records: data.max_total_records,
-            },
+            limits: state.limits.load().as_ref().clone(),
         }))
     }
 }
@@ -953,6 +1477,7 @@ impl FromRequest for ConfigRequest {
 pub struct HeartbeatRequest {
     pub headers: HeaderMap,
     pub db_pool: Box<dyn DbPool>,
+    pub health: Arc<Health>,
 }
 
 impl FromRequest for HeartbeatRequest {
@@ -987,7 +1512,12 @@ impl FromRequest for HeartbeatRequest {
                 }
             };
             let db_pool = state.db_pool.clone();
-            Ok(HeartbeatRequest { headers, db_pool })
+            let health = Arc::clone(&state.health);
+            Ok(HeartbeatRequest {
+                headers,
+                db_pool,
+                health,
+            })
         }
         .boxed_local()
     }
@@ -1021,6 +1551,74 @@ impl FromRequest for TestErrorRequest {
     }
 }
 
+/// Compare two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// An authorized request to the `/__admin__` endpoints.
+///
+/// Requires an `Authorization: Bearer <admin_token>` header matching the
+/// `admin_token` setting (or is rejected with a 401). All requests are
+/// forbidden (403) when it's unset.
+#[derive(Clone, Debug)]
+pub struct AdminRequest {
+    pub read_only: Arc<AtomicBool>,
+    pub reloader: Arc<Reloader>,
+}
+
+impl FromRequest for AdminRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = match req.app_data::<Data<ServerState>>() {
+            Some(s) => s,
+            None => {
+                error!("⚠️ Could not load the app state");
+                return future::err(
+                    ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        None,
+                    )
+                    .into(),
+                );
+            }
+        };
+        let token = match state.admin_token {
+            Some(ref token) if !token.is_empty() => token,
+            _ => return future::err(ApiError::from(ApiErrorKind::AdminDisabled).into()),
+        };
+        let authorized = req
+            .headers()
+            .get("authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| {
+                let mut parts = header.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(bearer)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        Some(bearer.trim())
+                    }
+                    _ => None,
+                }
+            })
+            .map_or(false, |bearer| {
+                constant_time_eq(bearer.as_bytes(), token.as_bytes())
+            });
+        if !authorized {
+            warn!("⚠️ Unauthorized admin request");
+            return future::err(ApiError::from(ApiErrorKind::AdminUnauthorized).into());
+        }
+        future::ok(AdminRequest {
+            read_only: Arc::clone(&state.read_only),
+            reloader: Arc::clone(&state.reloader),
+        })
+    }
+}
+
 /// Extract a user-identifier from the authentication token and validate against the URL
 ///
 /// This token should be adapted as needed for the storage system to store data
@@ -1177,49 +1775,75 @@ impl From<u32> for HawkIdentifier {
     }
 }
 
+/// Position at which to restart a paginated search.
+///
+/// Either a plain numeric offset ("<offset>") or, for `sort=newest`/`oldest`,
+/// a keyset position ("<timestamp>:<id>") of the last item returned: the
+/// next page begins immediately after it without OFFSET scanning.
 #[derive(Debug, Default, Clone, Deserialize, Validate)]
 #[serde(default)]
 pub struct Offset {
     pub timestamp: Option<SyncTimestamp>,
+    /// The id of the last item returned, breaking ties between items sharing
+    /// the same timestamp (base64url encoded when serialized)
+    pub id: Option<String>,
     pub offset: u64,
 }
 
+impl Offset {
+    /// Create a keyset position after the specified item
+    pub fn after(modified: SyncTimestamp, id: &str) -> Self {
+        Offset {
+            timestamp: Some(modified),
+            id: Some(id.to_owned()),
+            offset: 0,
+        }
+    }
+
+    /// Return the keyset position, if any
+    pub fn keyset(&self) -> Option<(SyncTimestamp, &str)> {
+        match (self.timestamp, self.id.as_ref()) {
+            (Some(timestamp), Some(id)) => Some((timestamp, id)),
+            _ => None,
+        }
+    }
+}
+
 impl ToString for Offset {
     fn to_string(&self) -> String {
-        match self.timestamp {
+        match self.keyset() {
             None => format!("{}", self.offset),
-            Some(ts) => format!("{}:{}", ts.as_i64(), self.offset),
+            Some((ts, id)) => format!(
+                "{}:{}",
+                ts.as_i64(),
+                base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+            ),
         }
     }
 }
 
 impl FromStr for Offset {
-    type Err = ParseIntError;
+    type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
-        let result = Offset {
-            timestamp: None,
-            offset: s.parse::<u64>()?,
-        };
-        /*
         let result = match s.chars().position(|c| c == ':') {
             None => Offset {
                 timestamp: None,
-                offset: s.parse::<u64>()?,
+                id: None,
+                offset: s.parse::<u64>().map_err(|e| e.to_string())?,
             },
-            Some(_colon_position) => {
-                let mut parts = s.split(':');
-                let timestamp_string = parts.next().unwrap_or("0");
-                let timestamp = SyncTimestamp::from_milliseconds(timestamp_string.parse::<u64>()?);
-                let offset = parts.next().unwrap_or("0").parse::<u64>()?;
-                Offset {
-                    timestamp: Some(timestamp),
-                    offset,
+            Some(colon_position) => {
+                let timestamp = s[..colon_position]
+                    .parse::<u64>()
+                    .map_err(|e| e.to_string())?;
+                let id = base64::decode_config(&s[colon_position + 1..], base64::URL_SAFE_NO_PAD)
+                    .map_err(|e| e.to_string())
+                    .and_then(|id| String::from_utf8(id).map_err(|e| e.to_string()))?;
+                if !VALID_ID_REGEX.is_match(&id) {
+                    return Err("Invalid offset id".to_owned());
                 }
+                Offset::after(SyncTimestamp::from_milliseconds(timestamp), &id)
             }
         };
-        */
         Ok(result)
     }
 }
@@ -1246,8 +1870,8 @@ pub struct BsoQueryParams {
     /// maximum number of items to return (integer)
     pub limit: Option<u32>,
 
-    /// position at which to restart search (string)
-    #[serde(deserialize_with = "deserialize_offset")]
+    /// position at which to restart search, from a signed cursor (string)
+    #[serde(skip)]
     pub offset: Option<Offset>,
 
     /// a comma-separated list of BSO ids (list of strings)
@@ -1260,6 +1884,13 @@ pub struct BsoQueryParams {
     pub full: bool,
 }
 
+/// The signed cursor form of `BsoQueryParams::offset`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct OffsetParam {
+    offset: Option<String>,
+}
+
 impl FromRequest for BsoQueryParams {
     type Config = ();
     type Error = Error;
@@ -1272,7 +1903,7 @@ impl FromRequest for BsoQueryParams {
         Box::pin(async move {
             let tags = Tags::from_request(&req, &mut payload).await?;
 
-            let params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
+            let mut params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
                 .map_err(|e| {
                     ValidationErrorKind::FromDetails(
                         e.to_string(),
@@ -1290,36 +1921,56 @@ impl FromRequest for BsoQueryParams {
                     Some(tags.clone()),
                 )
             })?;
-            // issue559: Dead code (timestamp always None)
-            /*
-               if params.sort != Sorting::Index {
-                   if let Some(timestamp) = params.offset.as_ref().and_then(|offset| offset.timestamp)
-                   {
-                       let bound = timestamp.as_i64();
-                       if let Some(newer) = params.newer {
-                           if bound < newer.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   format!("Invalid Offset {} {}", bound, newer.as_i64()),
-                                   RequestErrorLocation::QueryString,
-                                   Some("newer".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       } else if let Some(older) = params.older {
-                           if bound > older.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   "Invalid Offset".to_owned(),
-                                   RequestErrorLocation::QueryString,
-                                   Some("older".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       }
-                   }
-               }
-            */
+            let OffsetParam { offset } = Query::<OffsetParam>::from_request(&req, &mut payload)
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        None,
+                        Some(tags.clone()),
+                    )
+                })
+                .await?
+                .into_inner();
+            if let Some(offset) = offset {
+                let state = match req.app_data::<Data<ServerState>>() {
+                    Some(s) => s,
+                    None => {
+                        error!("⚠️ Could not load the app state");
+                        return Err(ValidationErrorKind::FromDetails(
+                            "Internal error".to_owned(),
+                            RequestErrorLocation::Unknown,
+                            Some("state".to_owned()),
+                            Some(tags),
+                        )
+                        .into());
+                    }
+                };
+                // Cursors are only valid for the user and collection whose
+                // query produced them
+                let uid = req
+                    .match_info()
+                    .get("uid")
+                    .and_then(|uid| uid.parse().ok())
+                    .unwrap_or_default();
+                let collection = req.match_info().get("collection").unwrap_or_default();
+                let offset = cursor::verify(
+                    &state.secrets.cursor_secret,
+                    &offset,
+                    uid,
+                    collection,
+                    &params,
+                )
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e,
+                        RequestErrorLocation::QueryString,
+                        Some("offset".to_owned()),
+                        Some(tags.clone()),
+                    )
+                })?;
+                params.offset = Some(offset);
+            }
             Ok(params)
         })
     }
@@ -1337,6 +1988,9 @@ pub struct BatchParams {
 pub struct BatchRequest {
     pub id: Option<String>,
     pub commit: bool,
+    /// The batch wide limits, enforced against the batch's actual contents
+    pub max_total_records: u32,
+    pub max_total_bytes: u32,
 }
 
 #[derive(Debug, Default, Clone, Deserialize)]
@@ -1381,7 +2035,10 @@ impl FromRequest for BatchRequestOpt {
                 }
             };
 
-            let limits = &state.limits;
+            let limits = state
+                .limits
+                .load()
+                .for_collection(req.match_info().get("collection").unwrap_or_default());
 
             let checks = [
                 (X_WEAVE_RECORDS, limits.max_post_records),
@@ -1472,6 +2129,8 @@ impl FromRequest for BatchRequestOpt {
                 opt: Some(BatchRequest {
                     id,
                     commit: params.commit.is_some(),
+                    max_total_records: limits.max_total_records,
+                    max_total_bytes: limits.max_total_bytes,
                 }),
             })
         })
@@ -1480,17 +2139,63 @@ impl FromRequest for BatchRequestOpt {
 
 /// PreCondition Header
 ///
-/// It's valid to include a X-If-Modified-Since or X-If-Unmodified-Since header but not
-/// both.
+/// It's valid to include one of X-If-Modified-Since, X-If-Unmodified-Since,
+/// If-None-Match or If-Match, but not several. Entity tags are derived from
+/// the resource's timestamp (see `SyncTimestamp::as_etag`): a BSO's are
+/// strong, while a collection's are weak (shared by its pages and formats),
+/// only matching If-None-Match.
 ///
 /// Used with Option<PreConditionHeader> to extract a possible PreConditionHeader.
 #[derive(Debug, Clone, PartialEq)]
 pub enum PreConditionHeader {
     IfModifiedSince(SyncTimestamp),
     IfUnmodifiedSince(SyncTimestamp),
+    IfNoneMatch(EntityTags),
+    IfMatch(EntityTags),
     NoHeader,
 }
 
+/// The entity tags listed in an If-Match/If-None-Match header
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityTags {
+    /// "*": matches any existing resource
+    Any,
+    /// The timestamps of the listed tags. Tags not issued by us never match
+    /// and are dropped
+    Tags(Vec<SyncTimestamp>),
+}
+
+impl EntityTags {
+    /// Parse a header value. Weak tags ("W/...") only match when `weak` (the
+    /// weak comparison of If-None-Match)
+    fn parse(value: &str, weak: bool) -> Self {
+        if value.trim() == "*" {
+            return EntityTags::Any;
+        }
+        EntityTags::Tags(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter_map(|tag| match (tag.starts_with("W/"), weak) {
+                    (true, true) => Some(&tag[2..]),
+                    (true, false) => None,
+                    (false, _) => Some(tag),
+                })
+                .filter_map(|tag| SyncTimestamp::from_etag(tag).ok())
+                .collect(),
+        )
+    }
+
+    /// Whether a resource last modified at `resource_ts` matches. Missing
+    /// resources have a timestamp of 0
+    pub fn matches(&self, resource_ts: SyncTimestamp) -> bool {
+        match self {
+            EntityTags::Any => resource_ts.as_i64() > 0,
+            EntityTags::Tags(tags) => tags.contains(&resource_ts),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PreConditionHeaderOpt {
     pub opt: Option<PreConditionHeader>,
@@ -1500,6 +2205,44 @@ impl PreConditionHeaderOpt {
     pub fn extrude(headers: &HeaderMap, tags: Option<Tags>) -> Result<Self, Error> {
         let modified = headers.get("X-If-Modified-Since");
         let unmodified = headers.get("X-If-Unmodified-Since");
+        let if_none_match = headers.get("If-None-Match");
+        let if_match = headers.get("If-Match");
+        let etag_header = if let Some(value) = if_none_match {
+            Some((value, "If-None-Match"))
+        } else if let Some(value) = if_match {
+            Some((value, "If-Match"))
+        } else {
+            None
+        };
+        if let Some((value, field_name)) = etag_header {
+            let count = [modified, unmodified, if_none_match, if_match]
+                .iter()
+                .filter(|header| header.is_some())
+                .count();
+            if count > 1 {
+                return Err(ValidationErrorKind::FromDetails(
+                    "conflicts with another precondition header".to_owned(),
+                    RequestErrorLocation::Header,
+                    Some(field_name.to_owned()),
+                    tags,
+                )
+                .into());
+            }
+            let value = value.to_str().map_err(|e| {
+                ValidationErrorKind::FromDetails(
+                    e.to_string(),
+                    RequestErrorLocation::Header,
+                    Some(field_name.to_owned()),
+                    tags.clone(),
+                )
+            })?;
+            let header = if field_name == "If-None-Match" {
+                PreConditionHeader::IfNoneMatch(EntityTags::parse(value, true))
+            } else {
+                PreConditionHeader::IfMatch(EntityTags::parse(value, false))
+            };
+            return Ok(Self { opt: Some(header) });
+        }
         if modified.is_some() && unmodified.is_some() {
             // TODO: See following error,
             return Err(ValidationErrorKind::FromDetails(
@@ -1703,17 +2446,6 @@ where
     }
 }
 
-fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<Offset>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let maybe_str: Option<String> = Deserialize::deserialize(deserializer)?;
-    if let Some(val) = maybe_str {
-        return Ok(Some(Offset::from_str(&val).map_err(SerdeError::custom)?));
-    }
-    Ok(None)
-}
-
 // Tokenserver extractor
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct TokenServerRequest {
@@ -1756,13 +2488,12 @@ mod tests {
     use sha2::Sha256;
 
     use crate::db::mock::{MockDb, MockDbPool};
-    use crate::server::{metrics, ServerState};
-    use crate::settings::{Secrets, ServerLimits, Settings};
+    use crate::server::{health::Health, metrics, reload::Reloader, shutdown::Drain, ServerState};
+    use crate::settings::{CollectionLimits, Secrets, ServerLimits, Settings};
 
     use crate::web::auth::{hkdf_expand_32, HawkPayload};
 
     lazy_static! {
-        static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
         static ref SECRETS: Arc<Secrets> = Arc::new(Secrets::new("Ted Koppel is a robot").unwrap());
         static ref USER_ID: u64 = thread_rng().gen_range(0, 10000);
         static ref USER_ID_STR: String = USER_ID.to_string();
@@ -1780,13 +2511,28 @@ mod tests {
     }
 
     fn make_state() -> ServerState {
-        let settings = Settings::default();
+        let settings = Settings {
+            reject_ua: vec![],
+            ..Settings::default()
+        };
+        let reloader = Arc::new(Reloader::new(&settings, None).unwrap());
         ServerState {
             db_pool: Box::new(MockDbPool::new()),
-            limits: Arc::clone(&SERVER_LIMITS),
+            limits: reloader.limits.clone(),
             secrets: Arc::clone(&SECRETS),
             port: 8000,
             metrics: Box::new(metrics::metrics_from_opts(&settings).unwrap()),
+            prometheus: None,
+            response_compression: settings.response_compression,
+            access_log: settings.access_log,
+            rate_limiter: Arc::clone(&reloader.rate_limiter),
+            reject_ua: reloader.reject_ua.clone(),
+            backoff: reloader.backoff.clone(),
+            read_only: Default::default(),
+            admin_token: settings.admin_token.clone(),
+            health: Arc::new(Health::new(&settings)),
+            drain: Arc::new(Drain::default()),
+            reloader,
         }
     }
 
@@ -1830,6 +2576,14 @@ mod tests {
     async fn post_collection(
         qs: &str,
         body: &serde_json::Value,
+    ) -> Result<CollectionPostRequest, Error> {
+        post_collection_encoded(qs, body.to_string().into_bytes(), "identity").await
+    }
+
+    async fn post_collection_encoded(
+        qs: &str,
+        body: Vec<u8>,
+        content_encoding: &str,
     ) -> Result<CollectionPostRequest, Error> {
         let payload = HawkPayload::test_default(*USER_ID);
         let state = make_state();
@@ -1839,7 +2593,6 @@ mod tests {
             if !qs.is_empty() { "?" } else { "" },
             qs
         );
-        let bod_str = body.to_string();
         let header =
             create_valid_hawk_header(&payload, &state, "POST", &path, TEST_HOST, TEST_PORT);
         let req = TestRequest::with_uri(&format!("http://{}:{}{}", TEST_HOST, TEST_PORT, path))
@@ -1847,8 +2600,9 @@ mod tests {
             .method(Method::POST)
             .header("authorization", header)
             .header("content-type", "application/json; charset=UTF-8")
+            .header("content-encoding", content_encoding)
             .header("accept", "application/json;q=0.9,/;q=0.2")
-            .set_payload(bod_str.to_owned())
+            .set_payload(body.clone())
             .param("uid", &USER_ID_STR)
             .param("collection", "tabs")
             .to_http_request();
@@ -1857,7 +2611,7 @@ mod tests {
         // Not sure why but sending req through *::extract loses the body.
         // Compose a payload here and call the *::from_request
         let (_sender, mut payload) = h1::Payload::create(true);
-        payload.unread_data(bytes::Bytes::from(bod_str.to_owned()));
+        payload.unread_data(bytes::Bytes::from(body));
         CollectionPostRequest::from_request(&req, &mut payload.into()).await
     }
 
@@ -1937,6 +2691,48 @@ mod tests {
         assert_eq!(result.full, true);
     }
 
+    #[test]
+    fn test_offset_query_args() {
+        let query = BsoQueryParams {
+            sort: Sorting::Newest,
+            ..Default::default()
+        };
+        let offset = cursor::sign(
+            &SECRETS.cursor_secret,
+            "1500:Ym8x".to_owned(),
+            *USER_ID,
+            "tabs",
+            &query,
+        )
+        .unwrap();
+        let req = TestRequest::with_uri(&format!("/?sort=newest&offset={}", offset))
+            .param("uid", &USER_ID_STR)
+            .param("collection", "tabs")
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.offset.unwrap().to_string(), "1500:Ym8x");
+
+        // Raw offsets and cursors reused for another query are rejected
+        for (uri, collection) in &[
+            ("/?sort=newest&offset=1500:Ym8x".to_owned(), "tabs"),
+            ("/?offset=10".to_owned(), "tabs"),
+            (format!("/?sort=oldest&offset={}", offset), "tabs"),
+            (format!("/?sort=newest&limit=5&offset={}", offset), "tabs"),
+            (format!("/?sort=newest&offset={}", offset), "history"),
+        ] {
+            let req = TestRequest::with_uri(uri)
+                .param("uid", &USER_ID_STR)
+                .param("collection", *collection)
+                .data(make_state())
+                .to_http_request();
+            let result = block_on(BsoQueryParams::extract(&req));
+            assert!(result.is_err());
+            let response: HttpResponse = result.err().unwrap().into();
+            assert_eq!(response.status(), 400);
+        }
+    }
+
     #[test]
     fn test_valid_bso_request() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2133,6 +2929,175 @@ mod tests {
         assert!(result.batch.is_none());
     }
 
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_gzip_collection_post_request() {
+        let bso_body = json!([
+            {"id": "123", "payload": "xxx", "sortindex": 23},
+            {"id": "456", "payload": "xxxasdf", "sortindex": 23}
+        ]);
+        let body = gzip(bso_body.to_string().as_bytes());
+        let result = post_collection_encoded("", body, "gzip")
+            .await
+            .expect("Could not get result in test_gzip_collection_post_request");
+        assert_eq!(result.bsos.valid.len(), 2);
+
+        // A small body inflating past max_request_bytes is rejected
+        let payload = "x".repeat(ServerLimits::default().max_request_bytes as usize);
+        let bso_body = json!([{"id": "123", "payload": payload}]);
+        let body = gzip(bso_body.to_string().as_bytes());
+        assert!(body.len() < 1024 * 1024);
+        let result = post_collection_encoded("", body, "gzip").await;
+        assert!(result.is_err());
+
+        let result = post_collection_encoded("", b"[]".to_vec(), "compress").await;
+        let response: HttpResponse = result.err().unwrap().into();
+        assert_eq!(response.status(), 415);
+    }
+
+    fn parse_bsos(newlines: bool, body: &[u8], chunk_size: usize) -> Result<BsoBodies, Error> {
+        let limits = ServerLimits::default();
+        let mut parser = BsoBodiesParser::new(
+            newlines,
+            limits.max_record_payload_bytes as usize,
+            limits.max_post_bytes as usize,
+            limits.max_ttl,
+        );
+        for chunk in body.chunks(chunk_size) {
+            parser.feed(chunk)?;
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn test_incremental_bso_bodies() {
+        let body = json!([
+            {"id": "1", "payload": "{\"a\": [\"}\"]}", "sortindex": 1},
+            {"id": "2", "payload": "x\\\"", "hop": "low"}
+        ])
+        .to_string();
+        let lines = "{\"id\": \"1\", \"payload\": \"]\"}\r\n{\"id\": \"2\"}";
+        for chunk_size in &[1, 3, 1024] {
+            let result = parse_bsos(false, body.as_bytes(), *chunk_size).unwrap();
+            assert_eq!(result.valid.len(), 1);
+            assert_eq!(result.valid[0].payload, Some("{\"a\": [\"}\"]}".to_owned()));
+            assert_eq!(result.invalid.len(), 1);
+
+            let result = parse_bsos(true, lines.as_bytes(), *chunk_size).unwrap();
+            assert_eq!(result.valid.len(), 2);
+            assert_eq!(result.valid[0].payload, Some("]".to_owned()));
+        }
+        assert!(parse_bsos(false, b" [ ] ", 1).unwrap().valid.is_empty());
+        assert!(parse_bsos(true, b"", 1).unwrap().valid.is_empty());
+
+        // Malformed records fail as soon as they arrive
+        let limits = ServerLimits::default();
+        let mut parser = BsoBodiesParser::new(false, 1024, limits.max_post_bytes as usize, None);
+        assert!(parser.feed(b"[{\"id\": \"1\"}, 1").is_err());
+        let mut parser = BsoBodiesParser::new(true, 1024, limits.max_post_bytes as usize, None);
+        assert!(parser.feed(b"{\"id\": \"1\"}\n{\"id\": \"1\"}\n").is_err());
+        let bodies: [&[u8]; 4] = [b"[{\"id\": \"1\"}", b"[{\"id\": \"1\"},]", b"[] x", b""];
+        for body in &bodies {
+            assert!(parse_bsos(false, body, 2).is_err());
+        }
+    }
+
+    #[test]
+    fn test_collection_limits() {
+        let mut limits = ServerLimits::default();
+        limits.collections.insert(
+            "history".to_owned(),
+            CollectionLimits {
+                max_record_payload_bytes: Some(4),
+                max_post_records: Some(2),
+                max_ttl: Some(60),
+                ..Default::default()
+            },
+        );
+        let history = limits.for_collection("history");
+        assert_eq!(history.max_record_payload_bytes, 4);
+        assert_eq!(history.max_ttl, Some(60));
+        assert_eq!(history.max_post_records, 2);
+        let tabs = limits.for_collection("tabs");
+        assert_eq!(
+            tabs.max_record_payload_bytes,
+            limits.max_record_payload_bytes
+        );
+        assert_eq!(tabs.max_ttl, None);
+
+        let mut parser = BsoBodiesParser::new(
+            false,
+            history.max_record_payload_bytes as usize,
+            history.max_post_bytes as usize,
+            history.max_ttl,
+        );
+        let body = json!([
+            {"id": "1", "payload": "xxx", "ttl": 60},
+            {"id": "2", "payload": "xxxxx"},
+            {"id": "3", "ttl": 61}
+        ]);
+        parser.feed(body.to_string().as_bytes()).unwrap();
+        let result = parser.finish().unwrap();
+        assert_eq!(result.valid.len(), 1);
+        assert_eq!(result.invalid["2"], "retry bytes");
+        assert_eq!(result.invalid["3"], "invalid ttl");
+
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "history": {"put": [{"id": "1", "payload": "xxx"}]},
+            "tabs": {"put": [{"id": "1", "payload": "xxxxx", "ttl": 61}]}
+        }))
+        .unwrap();
+        assert!(StorageTransactionRequest::validate_writes(raw, &limits).is_ok());
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "history": {"put": [{"id": "1", "ttl": 61}]}
+        }))
+        .unwrap();
+        assert!(StorageTransactionRequest::validate_writes(raw, &limits).is_err());
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "history": {"put": [{"id": "1", "payload": "xxx"}]},
+            "tabs": {}
+        }))
+        .unwrap();
+        assert!(StorageTransactionRequest::validate_writes(raw, &limits).is_err());
+
+        // A collection's puts are limited to its max_post_records
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "history": {"put": [{"id": "1"}, {"id": "2"}, {"id": "3"}]},
+            "tabs": {"put": [{"id": "1"}, {"id": "2"}, {"id": "3"}]}
+        }))
+        .unwrap();
+        assert_eq!(
+            StorageTransactionRequest::validate_writes(raw, &limits).unwrap_err(),
+            "size-limit-exceeded"
+        );
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "history": {"put": [{"id": "1"}, {"id": "2"}]},
+            "tabs": {"put": [{"id": "1"}, {"id": "2"}, {"id": "3"}]}
+        }))
+        .unwrap();
+        assert!(StorageTransactionRequest::validate_writes(raw, &limits).is_ok());
+
+        // And its payloads to its max_post_bytes
+        limits.max_post_bytes = 4;
+        limits.max_record_payload_bytes = 4;
+        let raw: BTreeMap<String, RawCollectionWrites> = serde_json::from_value(json!({
+            "tabs": {"put": [{"id": "1", "payload": "xxx"}, {"id": "2", "payload": "xx"}]}
+        }))
+        .unwrap();
+        assert_eq!(
+            StorageTransactionRequest::validate_writes(raw, &limits).unwrap_err(),
+            "size-limit-exceeded"
+        );
+    }
+
     #[actix_rt::test]
     async fn test_invalid_collection_post_request() {
         // Add extra fields, these will be invalid
@@ -2274,6 +3239,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_etag_precondition_headers() {
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-None-Match", "\"other\", W/\"32140\"")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(
+            result,
+            PreConditionHeader::IfNoneMatch(EntityTags::Tags(vec![SyncTimestamp::from_seconds(
+                32.14
+            )]))
+        );
+
+        // If-Match uses the strong comparison
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "W/\"32140\"")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(
+            result,
+            PreConditionHeader::IfMatch(EntityTags::Tags(vec![]))
+        );
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "*")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(result, PreConditionHeader::IfMatch(EntityTags::Any));
+        assert!(!EntityTags::Any.matches(SyncTimestamp::from_seconds(0.0)));
+
+        // Compressed representations' tags match their resource's timestamp
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "\"32140-gzip\", \"32150-br\", \"32160-zip\"")
+            .to_http_request();
+        let result = PreConditionHeaderOpt::extrude(&req.headers(), None)
+            .unwrap()
+            .opt
+            .unwrap();
+        assert_eq!(
+            result,
+            PreConditionHeader::IfMatch(EntityTags::Tags(vec![
+                SyncTimestamp::from_seconds(32.14),
+                SyncTimestamp::from_seconds(32.15)
+            ]))
+        );
+
+        let req = TestRequest::with_uri("/")
+            .data(make_state())
+            .header("If-Match", "\"32140\"")
+            .header("X-If-Unmodified-Since", "32.14")
+            .to_http_request();
+        assert!(PreConditionHeaderOpt::extrude(&req.headers(), None).is_err());
+    }
+
     #[test]
     fn valid_header_with_valid_path() {
         let hawk_payload = HawkPayload::test_default(*USER_ID);
@@ -2342,4 +3373,33 @@ mod tests {
         assert_eq!(result.bsos.invalid.len(), 1);
         assert!(result.bsos.invalid.contains_key("789"));
     }
+
+    #[test]
+    fn test_admin_request() {
+        let extract = |token: Option<&str>, header: Option<&str>| {
+            let mut state = make_state();
+            state.admin_token = token.map(ToOwned::to_owned);
+            let mut req = TestRequest::with_uri("/__admin__/read_only").data(state);
+            if let Some(header) = header {
+                req = req.header("authorization", header);
+            }
+            let req = req.to_http_request();
+            block_on(AdminRequest::extract(&req)).map_err(|e| {
+                let response: HttpResponse = e.into();
+                response.status()
+            })
+        };
+        assert!(extract(Some("s3cret"), Some("Bearer s3cret")).is_ok());
+        assert!(extract(Some("s3cret"), Some("bearer s3cret")).is_ok());
+        for (token, header) in &[
+            (Some("s3cret"), None),
+            (Some("s3cret"), Some("Bearer s3cre")),
+            (Some("s3cret"), Some("Hawk s3cret")),
+        ] {
+            assert_eq!(extract(*token, *header).unwrap_err(), 401);
+        }
+        for (token, header) in &[(None, Some("Bearer s3cret")), (Some(""), Some("Bearer "))] {
+            assert_eq!(extract(*token, *header).unwrap_err(), 403);
+        }
+    }
 }
@@ -1,17 +1,25 @@
 //! API Handlers
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 
-use actix_web::{http::StatusCode, Error, HttpRequest, HttpResponse};
+use actix_web::{
+    http::{header, StatusCode},
+    web, Error, HttpRequest, HttpResponse,
+};
 use futures::future::{self, Future};
-use serde::Serialize;
-use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use crate::db::transaction::DbTransactionPool;
 use crate::db::{params, results::Paginated, util::SyncTimestamp, Db, DbError, DbErrorKind};
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::server::{health::Level, ServerState};
+use crate::web::cursor;
+use crate::web::error::ValidationErrorKind;
 use crate::web::extractors::{
-    BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, ConfigRequest,
-    HeartbeatRequest, MetaRequest, ReplyFormat, TestErrorRequest,
+    AdminRequest, BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest,
+    ConfigRequest, HeartbeatRequest, MetaRequest, ReplyFormat, RequestErrorLocation,
+    StorageTransactionRequest, TestErrorRequest,
 };
 use crate::web::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS};
 
@@ -194,24 +202,38 @@ where
         .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
         .await?;
 
+    let next_offset = match result.offset {
+        Some(offset) => Some(cursor::sign(
+            &coll.cursor_secret,
+            offset,
+            coll.user_id.legacy_id,
+            &coll.collection,
+            &coll.query,
+        )?),
+        None => None,
+    };
+
     let mut builder = HttpResponse::build(StatusCode::OK);
     let resp = builder
         .header(X_LAST_MODIFIED, ts.as_header())
+        .header(header::ETAG, ts.as_weak_etag())
         .header(X_WEAVE_RECORDS, result.items.len().to_string())
-        .if_some(result.offset, |offset, resp| {
+        .if_some(next_offset, |offset, resp| {
             resp.header(X_WEAVE_NEXT_OFFSET, offset);
         });
 
     match coll.reply {
         ReplyFormat::Json => Ok(resp.json(result.items)),
         ReplyFormat::Newlines => {
-            let items: String = result
+            let items = result
                 .items
                 .into_iter()
-                .map(|v| serde_json::to_string(&v).unwrap_or_else(|_| "".to_string()))
-                .filter(|v| !v.is_empty())
-                .map(|v| v.replace("\n", "\\u000a") + "\n")
-                .collect();
+                .map(|v| serde_json::to_string(&v).map(|v| v.replace("\n", "\\u000a") + "\n"))
+                .collect::<Result<String, _>>()
+                .map_err(|e| {
+                    error!("Could not serialize a collection item: {:?}", e);
+                    ApiError::from(ApiErrorKind::Internal(e.to_string()))
+                })?;
 
             Ok(resp
                 .header("Content-Type", "application/newlines")
@@ -289,6 +311,51 @@ pub async fn post_collection_batch(
         .await?
     };
 
+    // The X-Weave-Total-* headers are only the client's declaration: check
+    // what's actually accumulated in the batch. Items re-sent replace their
+    // pending versions, so those aren't counted twice, but a re-send without
+    // a payload keeps its pending one (and its bytes)
+    let usage = if breq.id.is_some() {
+        db.get_batch_usage(params::GetBatchUsage {
+            user_id: coll.user_id.clone(),
+            collection: coll.collection.clone(),
+            id: id.clone(),
+            excluding: coll.bsos.valid.iter().map(|bso| bso.id.clone()).collect(),
+            excluding_payloads: coll
+                .bsos
+                .valid
+                .iter()
+                .filter(|bso| bso.payload.is_some())
+                .map(|bso| bso.id.clone())
+                .collect(),
+        })
+        .await?
+    } else {
+        Default::default()
+    };
+    let count = usage.count + coll.bsos.valid.len() as u64;
+    let total_bytes = usage.total_bytes
+        + coll
+            .bsos
+            .valid
+            .iter()
+            .map(|bso| {
+                bso.payload
+                    .as_ref()
+                    .map_or(0, |payload| payload.len() as u64)
+            })
+            .sum::<u64>();
+    if count > u64::from(breq.max_total_records) || total_bytes > u64::from(breq.max_total_bytes) {
+        let err: ApiError = ValidationErrorKind::FromDetails(
+            "size-limit-exceeded".to_owned(),
+            RequestErrorLocation::Body,
+            Some("bsos".to_owned()),
+            None,
+        )
+        .into();
+        return Err(err.into());
+    }
+
     let commit = breq.commit;
     let user_id = coll.user_id.clone();
     let collection = coll.collection.clone();
@@ -357,8 +424,6 @@ pub async fn post_collection_batch(
         })
         .await?;
 
-    // TODO: validate *actual* sizes of the batch items
-    // (max_total_records, max_total_bytes)
     let result = if let Some(batch) = batch {
         db.commit_batch(params::CommitBatch {
             user_id: user_id.clone(),
@@ -413,7 +478,11 @@ pub async fn get_bso(
 
             Ok(result.map_or_else(
                 || HttpResponse::NotFound().finish(),
-                |bso| HttpResponse::Ok().json(bso),
+                |bso| {
+                    HttpResponse::Ok()
+                        .header(header::ETAG, bso.modified.as_etag())
+                        .json(bso)
+                },
             ))
         })
         .await
@@ -439,50 +508,111 @@ pub async fn put_bso(
 
             Ok(HttpResponse::build(StatusCode::OK)
                 .header(X_LAST_MODIFIED, result.as_header())
+                .header(header::ETAG, result.as_etag())
                 .json(result))
         })
         .await
 }
 
+pub async fn post_storage_transaction(
+    txn_req: StorageTransactionRequest,
+    db_pool: DbTransactionPool,
+) -> Result<HttpResponse, Error> {
+    let collections = txn_req.collections.keys().cloned().collect();
+    let db_pool = db_pool.with_write_locks(collections);
+    db_pool
+        .transaction_http(|db| async move {
+            txn_req.metrics.incr("request.post_storage_transaction");
+            let mut modified = SyncTimestamp::default();
+            let mut timestamps = HashMap::new();
+            for (collection, writes) in txn_req.collections {
+                if !writes.delete.is_empty() {
+                    modified = db
+                        .delete_bsos(params::DeleteBsos {
+                            user_id: txn_req.user_id.clone(),
+                            collection: collection.clone(),
+                            ids: writes.delete,
+                        })
+                        .await?;
+                }
+                if !writes.put.is_empty() {
+                    let result = db
+                        .post_bsos(params::PostBsos {
+                            user_id: txn_req.user_id.clone(),
+                            collection: collection.clone(),
+                            bsos: writes.put.into_iter().map(From::from).collect(),
+                            failed: Default::default(),
+                        })
+                        .await?;
+                    // All or nothing: any failure aborts the entire transaction
+                    if !result.failed.is_empty() {
+                        let err: ApiError = ApiErrorKind::Internal(format!(
+                            "Failed to write {} bsos to {}",
+                            result.failed.len(),
+                            collection
+                        ))
+                        .into();
+                        return Err(err.into());
+                    }
+                    modified = result.modified;
+                }
+                timestamps.insert(collection, modified);
+            }
+
+            Ok(HttpResponse::build(StatusCode::OK)
+                .header(X_LAST_MODIFIED, modified.as_header())
+                .json(json!({
+                    "modified": modified,
+                    "collections": timestamps,
+                })))
+        })
+        .await
+}
+
 pub fn get_configuration(creq: ConfigRequest) -> impl Future<Output = Result<HttpResponse, Error>> {
     future::ready(Ok(HttpResponse::Ok().json(creq.limits)))
 }
 
 /** Returns a status message indicating the state of the current server
  *
+ * Each dependency's check is reported as "ok", "warn" or "fail": failures
+ * (and, when `heartbeat.warnings_fatal` is enabled, warnings) respond with a
+ * 503.
  */
 pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, Error> {
-    let mut checklist = HashMap::new();
-    checklist.insert(
-        "version".to_owned(),
-        Value::String(env!("CARGO_PKG_VERSION").to_owned()),
-    );
-    let db = hb.db_pool.get().await?;
-
-    match db.check().await {
-        Ok(result) => {
-            if result {
-                checklist.insert("database".to_owned(), Value::from("Ok"));
-            } else {
-                checklist.insert("database".to_owned(), Value::from("Err"));
-                checklist.insert(
-                    "database_msg".to_owned(),
-                    Value::from("check failed without error"),
-                );
-            };
-            let status = if result { "Ok" } else { "Err" };
-            checklist.insert("status".to_owned(), Value::from(status));
-            Ok(HttpResponse::Ok().json(checklist))
-        }
-        Err(e) => {
-            error!("Heartbeat error: {:?}", e);
-            checklist.insert("status".to_owned(), Value::from("Err"));
-            checklist.insert("database".to_owned(), Value::from("Unknown"));
-            Ok(HttpResponse::ServiceUnavailable().json(checklist))
-        }
+    let report = hb.health.check(hb.db_pool.as_ref()).await;
+    let status = report.status();
+    let database = match report.checks.get("database").map(|check| check.status) {
+        Some(Level::Fail) | None => "Err",
+        Some(_) => "Ok",
+    };
+    let checklist = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "status": match status {
+            Level::Ok => "Ok",
+            Level::Warn => "Warn",
+            Level::Fail => "Err",
+        },
+        "database": database,
+        "checks": report.checks,
+    });
+    if hb.health.is_fatal(status) {
+        Ok(HttpResponse::ServiceUnavailable().json(checklist))
+    } else {
+        Ok(HttpResponse::Ok().json(checklist))
     }
 }
 
+/// Used by the load balancers: fails once the server is shutting down
+pub async fn lbheartbeat(state: web::Data<ServerState>) -> HttpResponse {
+    let mut resp = if state.drain.is_draining() {
+        HttpResponse::ServiceUnavailable()
+    } else {
+        HttpResponse::Ok()
+    };
+    resp.content_type("application/json").body("{}")
+}
+
 // try returning an API error
 pub async fn test_error(
     _req: HttpRequest,
@@ -506,3 +636,45 @@ pub async fn test_error(
 
     Err(err)
 }
+
+/// Expose the metrics recorded when `prometheus_enabled` to Prometheus
+pub async fn prometheus_metrics(state: web::Data<ServerState>) -> HttpResponse {
+    match state.prometheus {
+        Some(ref registry) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(registry.render(&state.db_pool.state())),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// The state of the read-only maintenance mode
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReadOnlyState {
+    pub read_only: bool,
+}
+
+pub async fn get_read_only(admin: AdminRequest) -> HttpResponse {
+    HttpResponse::Ok().json(ReadOnlyState {
+        read_only: admin.read_only.load(Ordering::Relaxed),
+    })
+}
+
+/// Toggle the read-only maintenance mode, rejecting (or again accepting)
+/// storage writes
+pub async fn put_read_only(admin: AdminRequest, body: web::Json<ReadOnlyState>) -> HttpResponse {
+    let read_only = body.into_inner().read_only;
+    info!("Setting read_only: {}", read_only);
+    admin.read_only.store(read_only, Ordering::Relaxed);
+    HttpResponse::Ok().json(ReadOnlyState { read_only })
+}
+
+/// Reload the settings safe to change at runtime (as on SIGHUP)
+pub async fn reload(admin: AdminRequest) -> HttpResponse {
+    match admin.reloader.reload() {
+        Ok(()) => HttpResponse::Ok().json(json!({ "reloaded": true })),
+        Err(e) => {
+            error!("Could not reload the settings: {}", e);
+            HttpResponse::BadRequest().json(json!({ "reloaded": false, "error": e.to_string() }))
+        }
+    }
+}
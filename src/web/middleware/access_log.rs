@@ -0,0 +1,304 @@
+//! A structured (mozlog) access log record per request.
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{BodySize, MessageBody, ResponseBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, StatusCode},
+    Error, HttpMessage, HttpRequest,
+};
+use bytes::Bytes;
+use futures::future::{self, Either, LocalBoxFuture, MapOk, TryFutureExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::server::{ServerState, SYNC_VERSION_PATH};
+use crate::web::{middleware::prometheus::endpoint, X_WEAVE_RECORDS};
+
+/// Counts accumulated while handling a logged request, stored in its
+/// extensions
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    records: AtomicU64,
+    db_micros: AtomicU64,
+}
+
+impl RequestStats {
+    /// The stats of `req`, when it's logged
+    pub fn get(req: &HttpRequest) -> Option<Arc<RequestStats>> {
+        req.extensions().get::<Arc<RequestStats>>().cloned()
+    }
+
+    /// Count records sent by the client
+    pub fn add_records(&self, records: usize) {
+        self.records.fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    /// Count time spent in db transactions
+    pub fn add_db_time(&self, duration: Duration) {
+        self.db_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The uid and collection of a storage request's `path`
+fn path_params(path: &str) -> (Option<&str>, Option<&str>) {
+    let prefix = format!("/{}/", SYNC_VERSION_PATH);
+    if !path.starts_with(&prefix) {
+        return (None, None);
+    }
+    let mut elements = path[prefix.len()..].split('/');
+    let uid = elements.next().filter(|uid| !uid.is_empty());
+    let collection = match elements.next() {
+        Some("storage") => elements.next().filter(|collection| !collection.is_empty()),
+        _ => None,
+    };
+    (uid, collection)
+}
+
+/// A keyed hash of a `uid`, identifying the user's requests without
+/// revealing their (sequential, easily enumerated) id
+fn hash_uid(key: &[u8], uid: &str) -> String {
+    let mut hmac: Hmac<Sha256> = Hmac::new_varkey(key).expect("HMAC accepts any key length");
+    hmac.input(uid.as_bytes());
+    hmac.result().code()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether a request of `status` is logged at `sample_rate`
+fn sampled(sample_rate: f64, status: StatusCode) -> bool {
+    status.is_server_error() || sample_rate >= 1.0 || rand::random::<f64>() < sample_rate
+}
+
+/// The fields of a request's record
+struct AccessRecord {
+    uid: Option<String>,
+    method: String,
+    path: String,
+    collection: Option<String>,
+    bytes_in: Option<u64>,
+    stats: Arc<RequestStats>,
+    sample_rate: f64,
+    start: Instant,
+}
+
+impl AccessRecord {
+    fn log(&self, status: StatusCode, records_out: Option<u64>, bytes_out: Option<u64>) {
+        if !sampled(self.sample_rate, status) {
+            return;
+        }
+        let records = records_out.unwrap_or_else(|| self.stats.records.load(Ordering::Relaxed));
+        info!(
+            "request.summary";
+            "uid" => self.uid.as_deref(),
+            "method" => &self.method,
+            "path" => &self.path,
+            "collection" => self.collection.as_deref(),
+            "code" => status.as_u16(),
+            "bytes_in" => self.bytes_in,
+            "bytes_out" => bytes_out,
+            "records" => records,
+            "db_time_ms" => self.stats.db_micros.load(Ordering::Relaxed) / 1000,
+            "t" => self.start.elapsed().as_millis() as u64,
+        );
+    }
+}
+
+/// A response body counting its bytes, logging its request (if it has a
+/// `record`) once sent or abandoned
+pub struct LoggedBody<B> {
+    body: ResponseBody<B>,
+    record: Option<AccessRecord>,
+    status: StatusCode,
+    records_out: Option<u64>,
+    bytes_out: u64,
+}
+
+impl<B: MessageBody> MessageBody for LoggedBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
+        let poll = self.body.poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            self.bytes_out += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        if let Some(ref record) = self.record {
+            record.log(self.status, self.records_out, Some(self.bytes_out));
+        }
+    }
+}
+
+/// Pass through the response of a request that isn't logged
+fn unlogged<B>(resp: ServiceResponse<B>) -> ServiceResponse<LoggedBody<B>> {
+    resp.map_body(|head, body| {
+        ResponseBody::Body(LoggedBody {
+            body,
+            record: None,
+            status: head.status,
+            records_out: None,
+            bytes_out: 0,
+        })
+    })
+}
+
+/// Middleware logging a "request.summary" record of each request (when
+/// `access_log.enabled`), sampled at `access_log.sample_rate`.
+///
+/// Records include a hash of the uid, the route and collection, the status,
+/// bytes and records in or out, and the time spent in db transactions and in
+/// total (until the response is sent).
+#[derive(Debug, Default)]
+pub struct AccessLogger;
+
+impl<S, B> Transform<S> for AccessLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLoggerMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(AccessLoggerMiddleware { service }))
+    }
+}
+
+pub struct AccessLoggerMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLoggerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<
+        MapOk<S::Future, fn(ServiceResponse<B>) -> Self::Response>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let (sample_rate, secrets) = match sreq.app_data::<ServerState>() {
+            Some(state) if state.access_log.enabled => {
+                (state.access_log.sample_rate, Arc::clone(&state.secrets))
+            }
+            _ => {
+                return Either::Left(
+                    self.service
+                        .call(sreq)
+                        .map_ok(unlogged as fn(ServiceResponse<B>) -> Self::Response),
+                );
+            }
+        };
+        let (uid, collection) = path_params(sreq.path());
+        let stats = Arc::new(RequestStats::default());
+        let record = AccessRecord {
+            uid: uid.map(|uid| hash_uid(&secrets.access_log_secret, uid)),
+            method: sreq.method().to_string(),
+            path: endpoint(sreq.path()),
+            collection: collection.map(ToOwned::to_owned),
+            bytes_in: sreq
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+            stats: Arc::clone(&stats),
+            sample_rate,
+            start: Instant::now(),
+        };
+        sreq.extensions_mut().insert(stats);
+
+        let fut = self.service.call(sreq);
+        Either::Right(Box::pin(async move {
+            let resp = match fut.await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    record.log(e.as_response_error().status_code(), None, None);
+                    return Err(e);
+                }
+            };
+            let status = resp.status();
+            let records_out = resp
+                .headers()
+                .get(X_WEAVE_RECORDS)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            Ok(resp.map_body(|_, body| {
+                ResponseBody::Body(LoggedBody {
+                    body,
+                    record: Some(record),
+                    status,
+                    records_out,
+                    bytes_out: 0,
+                })
+            }))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::{hash_uid, path_params, sampled};
+
+    #[test]
+    fn test_path_params() {
+        assert_eq!(
+            path_params("/1.5/42/storage/tabs/abc"),
+            (Some("42"), Some("tabs"))
+        );
+        assert_eq!(
+            path_params("/1.5/42/storage/tabs"),
+            (Some("42"), Some("tabs"))
+        );
+        assert_eq!(path_params("/1.5/42/storage"), (Some("42"), None));
+        assert_eq!(path_params("/1.5/42/info/collections"), (Some("42"), None));
+        assert_eq!(path_params("/__heartbeat__"), (None, None));
+    }
+
+    #[test]
+    fn test_hash_uid() {
+        let hash = hash_uid(b"secret", "42");
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, hash_uid(b"secret", "42"));
+        assert_ne!(hash, hash_uid(b"secret", "43"));
+        assert_ne!(hash, hash_uid(b"other secret", "42"));
+    }
+
+    #[test]
+    fn test_sampled() {
+        assert!(sampled(1.0, StatusCode::OK));
+        assert!(!sampled(0.0, StatusCode::OK));
+        assert!(sampled(0.0, StatusCode::SERVICE_UNAVAILABLE));
+    }
+}
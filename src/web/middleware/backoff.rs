@@ -0,0 +1,217 @@
+//! Server-driven client backoff, configured or automatic on db pool saturation.
+use std::collections::HashMap;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method,
+    },
+    Error, HttpResponse,
+};
+use futures::future::{self, LocalBoxFuture, Ready, TryFutureExt};
+
+use crate::server::{metrics::Metrics, ServerState, SYNC_VERSION_PATH};
+use crate::settings::{Backoff, BackoffMode};
+use crate::web::{tags::Tags, DOCKER_FLOW_ENDPOINTS, X_WEAVE_BACKOFF};
+
+/// Why a request is backed off
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reason {
+    /// The configured `Backoff`
+    Configured,
+    /// The db pool is saturated
+    Pool,
+}
+
+impl Reason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Configured => "configured",
+            Reason::Pool => "pool",
+        }
+    }
+}
+
+/// The uid and collection of a storage request path:
+/// "/1.5/{uid}/storage/{collection}..."
+///
+/// Other paths (e.g. the "/__admin__/" endpoints) have no numeric uid and
+/// aren't storage requests.
+fn uid_and_collection(path: &str) -> Option<(u64, Option<&str>)> {
+    let prefix = format!("/{}/", SYNC_VERSION_PATH);
+    if !path.starts_with(&prefix) {
+        return None;
+    }
+    let mut elements = path[prefix.len()..].split('/');
+    let uid = elements.next()?.parse::<u64>().ok()?;
+    let collection = match elements.next() {
+        Some("storage") => elements.next().filter(|coll| !coll.is_empty()),
+        _ => None,
+    };
+    Some((uid, collection))
+}
+
+/// Determine if the configured `Backoff` applies to a request for `path`
+/// (only storage requests are backed off)
+fn applies(backoff: &Backoff, path: &str) -> bool {
+    if backoff.mode == BackoffMode::Off {
+        return false;
+    }
+    let (uid, collection) = match uid_and_collection(path) {
+        Some(params) => params,
+        None => return false,
+    };
+    if !backoff.collections.is_empty()
+        && !collection.map_or(false, |coll| backoff.collections.iter().any(|c| c == coll))
+    {
+        return false;
+    }
+    // Consistently back off the same users
+    backoff.user_percentage >= 100 || uid % 100 < u64::from(backoff.user_percentage)
+}
+
+/// Middleware asking clients to back off (Dockerflow endpoints are excluded).
+///
+/// Storage requests matching the configured `Backoff` get an
+/// `X-Weave-Backoff` header, or in `reject` mode writes are rejected with a 503 and
+/// `Retry-After`. All requests get the header while the db pool's saturation
+/// is past `Backoff::pool_saturation_percentage`.
+#[derive(Debug, Default)]
+pub struct ClientBackoff;
+
+impl<S, B> Transform<S> for ClientBackoff
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientBackoffMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ClientBackoffMiddleware { service })
+    }
+}
+
+pub struct ClientBackoffMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for ClientBackoffMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let path = sreq.uri().path().to_lowercase();
+        let state = match sreq.app_data::<ServerState>() {
+            Some(state) if !DOCKER_FLOW_ENDPOINTS.contains(&path.as_str()) => state,
+            _ => return Box::pin(self.service.call(sreq)),
+        };
+        let backoff = state.backoff.load();
+        let reason = if applies(&backoff, sreq.path()) {
+            Some(Reason::Configured)
+        } else if backoff.pool_saturation_percentage > 0
+            && state
+                .db_pool
+                .state()
+                .saturation()
+                .map_or(false, |saturation| {
+                    saturation >= u32::from(backoff.pool_saturation_percentage)
+                })
+        {
+            Some(Reason::Pool)
+        } else {
+            None
+        };
+        let reason = match reason {
+            Some(reason) => reason,
+            None => return Box::pin(self.service.call(sreq)),
+        };
+
+        let mut tags = HashMap::new();
+        tags.insert("reason".to_owned(), reason.as_str().to_owned());
+        Metrics::from(state.get_ref())
+            .incr_with_tags("request.backoff", Some(Tags::with_tags(tags)));
+
+        let seconds = backoff.seconds.to_string();
+        let write = !(sreq.method() == Method::GET || sreq.method() == Method::HEAD);
+        if reason == Reason::Configured && backoff.mode == BackoffMode::Reject && write {
+            return Box::pin(future::ok(
+                sreq.into_response(
+                    HttpResponse::ServiceUnavailable()
+                        .header(header::RETRY_AFTER, seconds.as_str())
+                        .header(X_WEAVE_BACKOFF, seconds.as_str())
+                        .body("0".to_owned())
+                        .into_body(),
+                ),
+            ));
+        }
+        Box::pin(self.service.call(sreq).and_then(move |mut resp| {
+            if let Ok(value) = HeaderValue::from_str(&seconds) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static(X_WEAVE_BACKOFF), value);
+            }
+            future::ok(resp)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{applies, uid_and_collection};
+    use crate::settings::{Backoff, BackoffMode};
+
+    #[test]
+    fn test_uid_and_collection() {
+        assert_eq!(
+            uid_and_collection("/1.5/42/storage/tabs/abc"),
+            Some((42, Some("tabs")))
+        );
+        assert_eq!(
+            uid_and_collection("/1.5/42/info/collections"),
+            Some((42, None))
+        );
+        assert_eq!(uid_and_collection("/1.5/42"), Some((42, None)));
+        assert_eq!(uid_and_collection("/1.5/"), None);
+        assert_eq!(uid_and_collection("/1.5/abc/storage/tabs"), None);
+        assert_eq!(uid_and_collection("/__admin__/read_only"), None);
+    }
+
+    #[test]
+    fn test_applies() {
+        let mut backoff = Backoff::default();
+        assert!(!applies(&backoff, "/1.5/42/storage/tabs"));
+
+        backoff.mode = BackoffMode::Header;
+        assert!(applies(&backoff, "/1.5/42/storage/tabs"));
+        assert!(applies(&backoff, "/1.5/42/info/collections"));
+        assert!(!applies(&backoff, "/__admin__/read_only"));
+        assert!(!applies(&backoff, "/__admin__/reload"));
+
+        backoff.collections = vec!["history".to_owned()];
+        assert!(!applies(&backoff, "/1.5/42/storage/tabs"));
+        assert!(!applies(&backoff, "/1.5/42/info/collections"));
+        assert!(applies(&backoff, "/1.5/42/storage/history"));
+
+        backoff.user_percentage = 50;
+        assert!(applies(&backoff, "/1.5/142/storage/history"));
+        assert!(!applies(&backoff, "/1.5/150/storage/history"));
+    }
+}
@@ -0,0 +1,361 @@
+//! Negotiated gzip/brotli compression of storage responses.
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::task::{Context, Poll};
+
+use actix_web::{
+    body::{Body, BodySize, MessageBody, ResponseBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderValue},
+    Error,
+};
+use brotli2::write::BrotliEncoder;
+use bytes::BytesMut;
+use flate2::{write::GzEncoder, Compression as GzCompression};
+use futures::future::{self, poll_fn, LocalBoxFuture};
+
+use crate::server::{metrics::Metrics, ServerState};
+use crate::web::{tags::Tags, DOCKER_FLOW_ENDPOINTS};
+
+/// Brotli quality level (0-11): favor speed, as responses are compressed
+/// on the fly
+const BROTLI_QUALITY: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Select the preferred encoding allowed by an Accept-Encoding header,
+    /// favoring brotli when equally weighted
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let (mut brotli, mut gzip, mut any) = (None, None, None);
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| {
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "br" => brotli = Some(quality),
+                "gzip" | "x-gzip" => gzip = Some(quality),
+                "*" => any = Some(quality),
+                _ => (),
+            }
+        }
+        let brotli = brotli.or(any).unwrap_or(0.0);
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        if brotli > 0.0 && brotli >= gzip {
+            Some(Encoding::Brotli)
+        } else if gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = BrotliEncoder::new(Vec::new(), BROTLI_QUALITY);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn tags(self) -> Tags {
+        let mut tags = HashMap::new();
+        tags.insert("encoding".to_owned(), self.as_str().to_owned());
+        Tags::with_tags(tags)
+    }
+}
+
+fn set_encoding_headers<B>(resp: &mut ServiceResponse<B>, encoding: Encoding) {
+    let headers = resp.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    // The encoded body is a different representation than the identity
+    // one: vary its strong entity tag by the encoding (stripped again by
+    // `SyncTimestamp::from_etag`, so it still satisfies If-Match)
+    let encoded_etag = headers
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/") && etag.len() >= 2 && etag.ends_with('"'))
+        .and_then(|etag| {
+            let tag = &etag[..etag.len() - 1];
+            HeaderValue::from_str(&format!("{}-{}\"", tag, encoding.as_str())).ok()
+        });
+    if let Some(encoded_etag) = encoded_etag {
+        headers.insert(header::ETAG, encoded_etag);
+    }
+}
+
+/// Pass a response through as is
+fn passthrough<B: MessageBody + 'static>(resp: ServiceResponse<B>) -> ServiceResponse<Body> {
+    resp.map_body(|_, body| ResponseBody::Other(Body::from_message(body)))
+}
+
+/// Middleware compressing storage responses per the client's Accept-Encoding
+/// (Dockerflow endpoints are excluded).
+///
+/// Only responses of a known size of at least
+/// `ResponseCompression::min_bytes` are compressed: streamed responses pass
+/// through untouched.
+#[derive(Debug, Default)]
+pub struct Compression;
+
+impl<S, B> Transform<S> for Compression
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CompressionMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(CompressionMiddleware { service }))
+    }
+}
+
+pub struct CompressionMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CompressionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let settings = match sreq.app_data::<ServerState>() {
+            Some(state) if state.response_compression.enabled => Some((
+                state.response_compression.min_bytes,
+                Metrics::from(state.get_ref()),
+            )),
+            _ => None,
+        };
+        let path = sreq.uri().path().to_lowercase();
+        let dockerflow = DOCKER_FLOW_ENDPOINTS.contains(&path.as_str());
+        let (min_bytes, metrics) = match settings {
+            Some(settings) if !dockerflow => settings,
+            _ => {
+                let fut = self.service.call(sreq);
+                return Box::pin(async move { fut.await.map(passthrough) });
+            }
+        };
+        let encoding = sreq
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Encoding::negotiate);
+
+        let fut = self.service.call(sreq);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+            let encoding = match encoding {
+                Some(encoding) if !resp.headers().contains_key(header::CONTENT_ENCODING) => {
+                    encoding
+                }
+                _ => return Ok(passthrough(resp)),
+            };
+            let size = match resp.response().body().size() {
+                BodySize::Sized(size) => size as u64,
+                BodySize::Sized64(size) => size,
+                _ => return Ok(passthrough(resp)),
+            };
+            if size < u64::from(min_bytes) {
+                return Ok(passthrough(resp));
+            }
+
+            let mut body = resp.response_mut().take_body();
+            let mut raw = BytesMut::with_capacity(size as usize);
+            while let Some(chunk) = poll_fn(|cx| body.poll_next(cx)).await {
+                raw.extend_from_slice(&chunk?);
+            }
+            let compressed = match encoding.encode(&raw) {
+                Ok(compressed) if compressed.len() < raw.len() => compressed,
+                result => {
+                    if let Err(e) = result {
+                        warn!("⚠️ Could not compress response: {:?}", e);
+                    }
+                    let raw = Body::from(raw.freeze());
+                    return Ok(resp.map_body(|_, _| ResponseBody::Other(raw)));
+                }
+            };
+            metrics.count_with_tags(
+                "storage.response.raw_bytes",
+                raw.len() as i64,
+                Some(encoding.tags()),
+            );
+            metrics.count_with_tags(
+                "storage.response.compressed_bytes",
+                compressed.len() as i64,
+                Some(encoding.tags()),
+            );
+
+            set_encoding_headers(&mut resp, encoding);
+            Ok(resp.map_body(|_, _| ResponseBody::Other(Body::from(compressed))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::db::mock::MockDbPool;
+    use crate::server::{health::Health, reload::Reloader, shutdown::Drain};
+    use crate::settings::{ResponseCompression, Secrets, Settings};
+
+    fn make_state(enabled: bool) -> ServerState {
+        let settings = Settings {
+            reject_ua: vec![],
+            ..Settings::default()
+        };
+        let reloader = Arc::new(Reloader::new(&settings, None).unwrap());
+        ServerState {
+            db_pool: Box::new(MockDbPool::new()),
+            limits: reloader.limits.clone(),
+            secrets: Arc::new(Secrets::default()),
+            port: 8000,
+            metrics: Box::new(Metrics::sink()),
+            prometheus: None,
+            response_compression: ResponseCompression {
+                enabled,
+                min_bytes: 1024,
+            },
+            access_log: Default::default(),
+            rate_limiter: Arc::clone(&reloader.rate_limiter),
+            reject_ua: reloader.reject_ua.clone(),
+            backoff: reloader.backoff.clone(),
+            read_only: Default::default(),
+            admin_token: None,
+            health: Arc::new(Health::new(&settings)),
+            drain: Arc::new(Drain::default()),
+            reloader,
+        }
+    }
+
+    async fn get(enabled: bool, path: &str, accept_encoding: &str) -> ServiceResponse<Body> {
+        let mut app = test::init_service(
+            App::new()
+                .data(make_state(enabled))
+                .wrap(Compression::default())
+                .route(
+                    "/small",
+                    web::get().to(|_: HttpRequest| HttpResponse::Ok().body("{}")),
+                )
+                .route(
+                    "/large",
+                    web::get().to(|_: HttpRequest| {
+                        HttpResponse::Ok()
+                            .header(header::ETAG, "\"42\"")
+                            .body("x".repeat(4096))
+                    }),
+                )
+                .route(
+                    "/__heartbeat__",
+                    web::get().to(|_: HttpRequest| HttpResponse::Ok().body("x".repeat(4096))),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::with_uri(path)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .to_request();
+        test::call_service(&mut app, req).await
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate("*;q=0.1, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_compresses_large_responses() {
+        let resp = get(true, "/large", "gzip").await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"42-gzip\"");
+        let body = test::read_body(resp).await;
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "x".repeat(4096));
+    }
+
+    #[actix_rt::test]
+    async fn test_skips_compression() {
+        for (enabled, path, accept_encoding) in &[
+            (true, "/small", "gzip"),
+            (true, "/large", "identity"),
+            (true, "/__heartbeat__", "gzip"),
+            (false, "/large", "gzip"),
+        ] {
+            let resp = get(*enabled, path, accept_encoding).await;
+            assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+            if *path == "/large" {
+                assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"42\"");
+            }
+        }
+    }
+}
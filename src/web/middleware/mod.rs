@@ -1,6 +1,13 @@
+pub mod access_log;
+pub mod backoff;
+pub mod compression;
 // pub mod db;
+pub mod prometheus;
+pub mod ratelimit;
+pub mod readonly;
 pub mod rejectua;
 pub mod sentry;
+pub mod tracing;
 pub mod weave;
 
 // # Web Middleware
@@ -0,0 +1,152 @@
+//! Request counters and latencies for the `/__metrics__` endpoint.
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::{self, FutureExt, LocalBoxFuture, Ready};
+
+use crate::server::{ServerState, SYNC_VERSION_PATH};
+use crate::web::DOCKER_FLOW_ENDPOINTS;
+
+/// The endpoints under "/1.5/{uid}" not under storage
+const INFO_ENDPOINTS: [&str; 6] = [
+    "info/collections",
+    "info/collection_counts",
+    "info/collection_usage",
+    "info/configuration",
+    "info/quota",
+    "1.0/sync/1.5",
+];
+
+const ADMIN_ENDPOINTS: [&str; 2] = ["/__admin__/read_only", "/__admin__/reload"];
+
+/// The route pattern of a request for `path`, e.g.
+/// "/1.5/{uid}/storage/{collection}". Unknown paths are all "other", to
+/// bound the number of label values.
+pub fn endpoint(path: &str) -> String {
+    let prefix = format!("/{}/", SYNC_VERSION_PATH);
+    if !path.starts_with(&prefix) {
+        let path = path.to_lowercase();
+        if DOCKER_FLOW_ENDPOINTS.contains(&path.as_str())
+            || ADMIN_ENDPOINTS.contains(&path.as_str())
+        {
+            return path;
+        }
+        return "other".to_owned();
+    }
+    let mut elements = path[prefix.len()..].splitn(2, '/');
+    let pattern = format!("{}{{uid}}", prefix);
+    elements.next();
+    let rest = match elements.next() {
+        None | Some("") => return pattern,
+        Some(rest) => rest.trim_end_matches('/'),
+    };
+    if INFO_ENDPOINTS.contains(&rest) {
+        return format!("{}/{}", pattern, rest);
+    }
+    let mut storage = rest.splitn(3, '/');
+    if storage.next() != Some("storage") {
+        return "other".to_owned();
+    }
+    match (storage.next(), storage.next()) {
+        (None, _) => format!("{}/storage", pattern),
+        (Some(_), None) => format!("{}/storage/{{collection}}", pattern),
+        (Some(_), Some(_)) => format!("{}/storage/{{collection}}/{{bso}}", pattern),
+    }
+}
+
+/// Middleware recording every request's endpoint, method, status and
+/// latency when `prometheus_enabled`.
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics;
+
+impl<S, B> Transform<S> for PrometheusMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = PrometheusMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(PrometheusMetricsMiddleware { service })
+    }
+}
+
+pub struct PrometheusMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for PrometheusMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let registry = match sreq
+            .app_data::<ServerState>()
+            .and_then(|state| state.prometheus.clone())
+        {
+            Some(registry) => registry,
+            None => return Box::pin(self.service.call(sreq)),
+        };
+        let endpoint = endpoint(sreq.path());
+        let method = sreq.method().to_string();
+        let start = Instant::now();
+        Box::pin(self.service.call(sreq).map(move |result| {
+            let status = match result {
+                Ok(ref resp) => resp.status(),
+                Err(ref e) => e.as_response_error().status_code(),
+            };
+            registry.record_request(&endpoint, &method, status.as_u16(), start.elapsed());
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::endpoint;
+
+    #[test]
+    fn test_endpoint() {
+        for (path, expected) in &[
+            ("/1.5/42", "/1.5/{uid}"),
+            ("/1.5/42/", "/1.5/{uid}"),
+            ("/1.5/42/info/collections", "/1.5/{uid}/info/collections"),
+            ("/1.5/42/info/quota", "/1.5/{uid}/info/quota"),
+            ("/1.5/42/info/wibble", "other"),
+            ("/1.5/42/storage", "/1.5/{uid}/storage"),
+            ("/1.5/42/storage/tabs", "/1.5/{uid}/storage/{collection}"),
+            ("/1.5/42/storage/tabs/", "/1.5/{uid}/storage/{collection}"),
+            (
+                "/1.5/42/storage/tabs/a/b",
+                "/1.5/{uid}/storage/{collection}/{bso}",
+            ),
+            ("/__heartbeat__", "/__heartbeat__"),
+            ("/__admin__/read_only", "/__admin__/read_only"),
+            ("/__admin__/reload", "/__admin__/reload"),
+            ("/wibble", "other"),
+        ] {
+            assert_eq!(endpoint(path), *expected, "{}", path);
+        }
+    }
+}
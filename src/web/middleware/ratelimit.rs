@@ -0,0 +1,435 @@
+//! Token bucket rate limiting of storage requests, per user and per client IP.
+#![allow(clippy::type_complexity)]
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method, StatusCode},
+    Error, HttpResponse,
+};
+use futures::future::{self, Either, Ready};
+
+use crate::server::{metrics::Metrics, reload::Reloadable, ServerState};
+use crate::settings::{RateLimit, RateLimits};
+use crate::web::{middleware::SyncServerRequest, tags::Tags, DOCKER_FLOW_ENDPOINTS};
+
+/// Buckets beyond this many trigger pruning of the idle (full) ones
+const MAX_BUCKETS: usize = 100_000;
+
+/// How often the buckets may be pruned, bounding the cost of scanning them
+/// while they're all in use
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The budget a request draws from
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum BucketKind {
+    UserReads,
+    UserWrites,
+    IpReads,
+    IpWrites,
+}
+
+impl BucketKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            BucketKind::UserReads => "user_reads",
+            BucketKind::UserWrites => "user_writes",
+            BucketKind::IpReads => "ip_reads",
+            BucketKind::IpWrites => "ip_writes",
+        }
+    }
+
+    fn limit(self, limits: &RateLimits) -> RateLimit {
+        match self {
+            BucketKind::UserReads => limits.user_reads,
+            BucketKind::UserWrites => limits.user_writes,
+            BucketKind::IpReads => limits.ip_reads,
+            BucketKind::IpWrites => limits.ip_writes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(limit.per_second))
+            .min(f64::from(limit.burst.max(1)));
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: RateLimit) -> bool {
+        self.tokens >= f64::from(limit.burst.max(1))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<(BucketKind, String), Bucket>,
+    pruned: Option<Instant>,
+}
+
+impl Buckets {
+    /// Make room for another bucket: prune the idle ones (at most every
+    /// `PRUNE_INTERVAL`), evicting an arbitrary one when they're all in use
+    fn make_room(&mut self, limits: &RateLimits, now: Instant) {
+        if self.buckets.len() < MAX_BUCKETS {
+            return;
+        }
+        let prune = self
+            .pruned
+            .map_or(true, |pruned| now.duration_since(pruned) >= PRUNE_INTERVAL);
+        if prune {
+            self.buckets.retain(|(kind, _), bucket| {
+                let limit = kind.limit(limits);
+                bucket.refill(limit, now);
+                !bucket.is_full(limit)
+            });
+            self.pruned = Some(now);
+        }
+        if self.buckets.len() >= MAX_BUCKETS {
+            let evicted = self.buckets.keys().next().cloned();
+            if let Some(evicted) = evicted {
+                self.buckets.remove(&evicted);
+            }
+        }
+    }
+
+    /// The refilled `kind` bucket of `id`, starting full
+    fn get(
+        &mut self,
+        kind: BucketKind,
+        id: &str,
+        limits: &RateLimits,
+        now: Instant,
+    ) -> &mut Bucket {
+        let limit = kind.limit(limits);
+        let key = (kind, id.to_owned());
+        if !self.buckets.contains_key(&key) {
+            self.make_room(limits, now);
+        }
+        let bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: f64::from(limit.burst.max(1)),
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        bucket
+    }
+}
+
+/// The token buckets of all users and client IPs, shared between workers.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Reloadable<RateLimits>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: Reloadable::new(limits),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    pub fn limits(&self) -> Arc<RateLimits> {
+        self.limits.load()
+    }
+
+    /// Replace the limits (e.g. on reload), keeping the buckets
+    pub fn set_limits(&self, limits: RateLimits) {
+        self.limits.store(limits);
+    }
+
+    /// Take a token from each of the `wanted` buckets (of a kind and id),
+    /// only when they all have one. Otherwise returns the first exhausted
+    /// bucket and how long until it has one
+    fn acquire(
+        &self,
+        wanted: &[(BucketKind, &str)],
+        now: Instant,
+    ) -> Result<(), (BucketKind, Duration)> {
+        let limits = self.limits();
+        // A rate of 0 is unlimited
+        let wanted: Vec<_> = wanted
+            .iter()
+            .filter(|(kind, _)| kind.limit(&limits).per_second > 0)
+            .collect();
+        let mut buckets = self.buckets.lock().expect("Rate limit buckets poisoned");
+        for (kind, id) in &wanted {
+            let bucket = buckets.get(*kind, id, &limits, now);
+            if bucket.tokens < 1.0 {
+                let wait = (1.0 - bucket.tokens) / f64::from(kind.limit(&limits).per_second);
+                return Err((*kind, Duration::from_secs_f64(wait)));
+            }
+        }
+        for (kind, id) in &wanted {
+            buckets.get(*kind, id, &limits, now).tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Check a request against the client IP's and the user's buckets,
+    /// drawing from both only when neither's exhausted
+    fn check(
+        &self,
+        write: bool,
+        ip: &str,
+        uid: Option<&str>,
+    ) -> Result<(), (BucketKind, Duration)> {
+        let (ip_kind, user_kind) = if write {
+            (BucketKind::IpWrites, BucketKind::UserWrites)
+        } else {
+            (BucketKind::IpReads, BucketKind::UserReads)
+        };
+        let mut wanted = vec![(ip_kind, ip)];
+        if let Some(uid) = uid {
+            wanted.push((user_kind, uid));
+        }
+        self.acquire(&wanted, Instant::now())
+    }
+}
+
+/// Parse an address of X-Forwarded-For, which may include a port
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    let addr = addr.trim();
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The client's IP: the peer's, unless it's one of the `trusted_proxies`.
+///
+/// Proxies append the address they received a request from to
+/// X-Forwarded-For, so the client's is its rightmost address not of a
+/// trusted proxy. Addresses left of that are client supplied, and ignored.
+fn client_ip(sreq: &ServiceRequest, trusted_proxies: &[IpAddr]) -> String {
+    let mut ip = match sreq.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return String::new(),
+    };
+    if trusted_proxies.contains(&ip) {
+        let forwarded: Vec<_> = sreq
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for addr in forwarded.into_iter().rev() {
+            match parse_ip(addr) {
+                Some(addr) => ip = addr,
+                None => break,
+            }
+            if !trusted_proxies.contains(&ip) {
+                break;
+            }
+        }
+    }
+    ip.to_string()
+}
+
+/// Middleware throttling storage requests exceeding the `RateLimits`
+/// (Dockerflow endpoints are excluded).
+///
+/// Throttled requests are answered with the configured status (429 or 503)
+/// and a `Retry-After` of when the exhausted bucket allows another request.
+#[derive(Debug, Default)]
+pub struct RateLimiting;
+
+impl<S, B> Transform<S> for RateLimiting
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(RateLimitingMiddleware { service })
+    }
+}
+
+pub struct RateLimitingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RateLimitingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<Ready<Result<Self::Response, Self::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let path = sreq.uri().path().to_lowercase();
+        let state = match sreq.app_data::<ServerState>() {
+            Some(state) if state.rate_limiter.limits().enabled => state,
+            _ => return Either::Right(self.service.call(sreq)),
+        };
+        if DOCKER_FLOW_ENDPOINTS.contains(&path.as_str()) {
+            return Either::Right(self.service.call(sreq));
+        }
+
+        let write = !(sreq.method() == Method::GET || sreq.method() == Method::HEAD);
+        let ip = client_ip(&sreq, &state.rate_limiter.limits().trusted_proxies);
+        // Requests failing authentication are only limited by IP, they're
+        // rejected further down the line
+        let uid = sreq
+            .get_hawk_id()
+            .ok()
+            .map(|user_id| user_id.legacy_id.to_string());
+        let (kind, wait) = match state.rate_limiter.check(write, &ip, uid.as_deref()) {
+            Ok(()) => return Either::Right(self.service.call(sreq)),
+            Err(exhausted) => exhausted,
+        };
+
+        debug!("Rate limiting {:?} bucket", kind.as_str());
+        let mut tags = HashMap::new();
+        tags.insert("bucket".to_owned(), kind.as_str().to_owned());
+        Metrics::from(state.get_ref())
+            .incr_with_tags("request.rate_limited", Some(Tags::with_tags(tags)));
+
+        let status = StatusCode::from_u16(state.rate_limiter.limits().status)
+            .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        Either::Left(future::ok(
+            sreq.into_response(
+                HttpResponse::build(status)
+                    .header(header::RETRY_AFTER, retry_after.to_string())
+                    .body("0".to_owned())
+                    .into_body(),
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use actix_web::test::TestRequest;
+
+    use super::{client_ip, BucketKind, RateLimiter, MAX_BUCKETS};
+    use crate::settings::{RateLimit, RateLimits};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            enabled: true,
+            user_reads: RateLimit::new(1, 2),
+            user_writes: RateLimit::new(0, 0),
+            ip_reads: RateLimit::new(10, 3),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let user = |id| [(BucketKind::UserReads, id)];
+        assert!(limiter.acquire(&user("1"), now).is_ok());
+        assert!(limiter.acquire(&user("1"), now).is_ok());
+        let wait = limiter.acquire(&user("1"), now).unwrap_err();
+        assert_eq!(wait, (BucketKind::UserReads, Duration::from_secs(1)));
+        // Buckets are per id
+        assert!(limiter.acquire(&user("2"), now).is_ok());
+        // And refill over time
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.acquire(&user("1"), later).is_ok());
+        assert!(limiter.acquire(&user("1"), later).is_err());
+        // A rate of 0 is unlimited
+        for _ in 0..10 {
+            assert!(limiter
+                .acquire(&[(BucketKind::UserWrites, "1")], now)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = limiter();
+        for _ in 0..2 {
+            assert!(limiter.check(false, "127.0.0.1", Some("1")).is_ok());
+        }
+        let (kind, _) = limiter.check(false, "127.0.0.1", Some("1")).unwrap_err();
+        assert_eq!(kind, BucketKind::UserReads);
+        // The throttled request didn't draw from the IP's bucket
+        assert!(limiter.check(false, "127.0.0.1", Some("2")).is_ok());
+        // Which is now exhausted
+        assert!(limiter.check(false, "127.0.0.1", None).is_err());
+        let (kind, _) = limiter.check(false, "127.0.0.1", Some("3")).unwrap_err();
+        assert_eq!(kind, BucketKind::IpReads);
+        // Without drawing from the user's bucket either
+        let now = Instant::now();
+        assert!(limiter
+            .acquire(&[(BucketKind::UserReads, "3")], now)
+            .is_ok());
+        assert!(limiter
+            .acquire(&[(BucketKind::UserReads, "3")], now)
+            .is_ok());
+        // Writes draw from their own buckets
+        assert!(limiter.check(true, "127.0.0.1", Some("1")).is_ok());
+    }
+
+    #[test]
+    fn test_bounded_buckets() {
+        let limiter = limiter();
+        let now = Instant::now();
+        // Every bucket's in use, so none can be pruned
+        for id in 0..=MAX_BUCKETS {
+            assert!(limiter
+                .acquire(&[(BucketKind::UserReads, id.to_string().as_str())], now)
+                .is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), MAX_BUCKETS);
+        assert_eq!(buckets.pruned, Some(now));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = |peer: &str, forwarded: &str| {
+            TestRequest::default()
+                .peer_addr(peer.parse::<SocketAddr>().unwrap())
+                .header("X-Forwarded-For", forwarded)
+                .to_srv_request()
+        };
+
+        // X-Forwarded-For is ignored unless sent by a trusted proxy
+        let sreq = request("192.0.2.1:4000", "198.51.100.1");
+        assert_eq!(client_ip(&sreq, &[]), "192.0.2.1");
+        assert_eq!(client_ip(&sreq, &[proxy]), "192.0.2.1");
+
+        // Only the addresses appended by trusted proxies are believed
+        let sreq = request("10.0.0.1:4000", "203.0.113.9, 198.51.100.1");
+        assert_eq!(client_ip(&sreq, &[proxy]), "198.51.100.1");
+        let sreq = request("10.0.0.1:4000", "198.51.100.1, 10.0.0.1");
+        assert_eq!(client_ip(&sreq, &[proxy]), "198.51.100.1");
+        let sreq = request("10.0.0.1:4000", "garbage");
+        assert_eq!(client_ip(&sreq, &[proxy]), "10.0.0.1");
+    }
+}
@@ -0,0 +1,108 @@
+//! Read-only maintenance mode, rejecting storage writes while it's enabled.
+#![allow(clippy::type_complexity)]
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpResponse,
+};
+use futures::future::{self, Either, Ready};
+
+use crate::server::{metrics::Metrics, ServerState, SYNC_VERSION_PATH};
+use crate::web::X_WEAVE_BACKOFF;
+
+/// Determine if a `method` request for `path` writes to storage
+fn is_storage_write(method: &Method, path: &str) -> bool {
+    let write = *method == Method::PUT || *method == Method::POST || *method == Method::DELETE;
+    write && path.starts_with(&format!("/{}/", SYNC_VERSION_PATH))
+}
+
+/// Middleware rejecting storage writes with a 503 while the server is in
+/// read-only mode (`ServerState::read_only`).
+///
+/// Reads, including the `/info/*` endpoints, are unaffected. Clients are told
+/// to retry after `Backoff::seconds` via `Retry-After` and `X-Weave-Backoff`.
+#[derive(Debug, Default)]
+pub struct ReadOnly;
+
+impl<S, B> Transform<S> for ReadOnly
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ReadOnlyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ReadOnlyMiddleware { service })
+    }
+}
+
+pub struct ReadOnlyMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for ReadOnlyMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<Ready<Result<Self::Response, Self::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let state = match sreq.app_data::<ServerState>() {
+            Some(state) if state.read_only.load(Ordering::Relaxed) => state,
+            _ => return Either::Right(self.service.call(sreq)),
+        };
+        if !is_storage_write(sreq.method(), sreq.path()) {
+            return Either::Right(self.service.call(sreq));
+        }
+
+        Metrics::from(state.get_ref()).incr("request.read_only");
+        let seconds = state.backoff.load().seconds.to_string();
+        Either::Left(future::ok(
+            sreq.into_response(
+                HttpResponse::ServiceUnavailable()
+                    .header(header::RETRY_AFTER, seconds.as_str())
+                    .header(X_WEAVE_BACKOFF, seconds.as_str())
+                    .body("0".to_owned())
+                    .into_body(),
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::Method;
+
+    use super::is_storage_write;
+
+    #[test]
+    fn test_is_storage_write() {
+        for method in &[Method::PUT, Method::POST, Method::DELETE] {
+            assert!(is_storage_write(method, "/1.5/42/storage/tabs/abc"));
+            assert!(is_storage_write(method, "/1.5/42/storage"));
+            assert!(is_storage_write(method, "/1.5/42"));
+            assert!(!is_storage_write(method, "/__admin__/read_only"));
+        }
+        assert!(!is_storage_write(&Method::GET, "/1.5/42/storage/tabs"));
+        assert!(!is_storage_write(&Method::HEAD, "/1.5/42/storage/tabs"));
+        assert!(!is_storage_write(&Method::GET, "/1.5/42/info/collections"));
+    }
+}
@@ -3,34 +3,98 @@ use std::task::{Context, Poll};
 
 use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    http::header::USER_AGENT,
+    http::{header::USER_AGENT, StatusCode},
     Error, HttpResponse,
 };
 use futures::future::{self, Either, Ready};
-use lazy_static::lazy_static;
 use regex::Regex;
 
+use crate::error::{ApiError, ApiErrorKind};
 use crate::server::{metrics::Metrics, ServerState};
+use crate::settings::RejectUARule;
 
-lazy_static! {
-    // e.g. "Firefox-iOS-Sync/18.0b1 (iPhone; iPhone OS 13.2.2) (Fennec (synctesting))"
-    // https://github.com/mozilla-mobile/firefox-ios/blob/v19.x/Shared/UserAgent.swift#L12
-    static ref IOS_UA_REGEX: Regex = Regex::new(
-        r"(?x)
-^
-Firefox-iOS-Sync/
-(?P<major>[0-9]+)\.[.0-9]+    # <appVersion-major>.<appVersion-minor-etc>
-b.*                           # b<buildNumber>
-\s\(.+                        #  (<deviceModel>
-;\siPhone\sOS                 # ; iPhone OS
-\s.+\)                        #  <systemVersion>)
-\s\(.*\)                      #  (<displayName>)
-$
-"
-    )
-    .unwrap();
+/// A compiled `RejectUARule`
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    version_capture: Option<String>,
+    min_version: Option<u32>,
+    max_version: Option<u32>,
+    status: StatusCode,
+    body: String,
+    paths: Vec<Regex>,
 }
 
+impl Rule {
+    fn new(rule: &RejectUARule) -> Result<Self, ApiError> {
+        let compile = |regex: &str| {
+            Regex::new(regex).map_err(|e| -> ApiError {
+                ApiErrorKind::Internal(format!("Invalid reject_ua regex {:?}: {}", regex, e)).into()
+            })
+        };
+        let status = StatusCode::from_u16(rule.status).map_err(|e| -> ApiError {
+            ApiErrorKind::Internal(format!("Invalid reject_ua status: {}", e)).into()
+        })?;
+        Ok(Rule {
+            regex: compile(&rule.regex)?,
+            version_capture: rule.version_capture.clone(),
+            min_version: rule.min_version,
+            max_version: rule.max_version,
+            status,
+            body: rule.body.clone(),
+            paths: rule
+                .paths
+                .iter()
+                .map(|path| compile(path))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Determine if a request for `path` from `ua` should be rejected
+    fn should_reject(&self, path: &str, ua: &str) -> bool {
+        if !self.paths.is_empty() && !self.paths.iter().any(|regex| regex.is_match(path)) {
+            return false;
+        }
+        let captures = match self.regex.captures(ua) {
+            Some(captures) => captures,
+            None => return false,
+        };
+        let name = match self.version_capture {
+            Some(ref name) => name,
+            None => return true,
+        };
+        match captures
+            .name(name)
+            .and_then(|version| version.as_str().parse::<u32>().ok())
+        {
+            Some(version) => {
+                self.min_version.map_or(true, |min| min <= version)
+                    && self.max_version.map_or(true, |max| version <= max)
+            }
+            None => false,
+        }
+    }
+}
+
+/// The `RejectUARule`s from the settings, compiled at startup.
+#[derive(Debug, Default)]
+pub struct RejectUARules(Vec<Rule>);
+
+impl RejectUARules {
+    pub fn new(rules: &[RejectUARule]) -> Result<Self, ApiError> {
+        Ok(RejectUARules(
+            rules.iter().map(Rule::new).collect::<Result<_, _>>()?,
+        ))
+    }
+
+    /// The first rule rejecting a request for `path` from `ua`
+    fn find(&self, path: &str, ua: &str) -> Option<&Rule> {
+        self.0.iter().find(|rule| rule.should_reject(path, ua))
+    }
+}
+
+/// Middleware rejecting requests from User-Agents matching the `reject_ua`
+/// rules with an error response.
 #[derive(Debug, Default)]
 pub struct RejectUA;
 
@@ -72,51 +136,94 @@ where
     }
 
     fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
-        match sreq.headers().get(USER_AGENT) {
-            Some(header) if header.to_str().map_or(false, should_reject) => {
-                let state = match &sreq.app_data::<ServerState>() {
-                    Some(v) => v.clone(),
-                    None => {
-                        return Either::Left(future::ok(
-                            sreq.into_response(
-                                HttpResponse::InternalServerError()
-                                    .body("Err: No State".to_owned())
-                                    .into_body(),
-                            ),
-                        ))
-                    }
-                };
-                debug!("Rejecting User-Agent: {:?}", header);
-                Metrics::from(state.as_ref()).incr("error.rejectua");
-
-                Either::Left(future::ok(
+        let ua = match sreq.headers().get(USER_AGENT) {
+            Some(header) => match header.to_str() {
+                Ok(ua) => ua.to_owned(),
+                Err(_) => return Either::Right(self.service.call(sreq)),
+            },
+            None => return Either::Right(self.service.call(sreq)),
+        };
+        let state = match &sreq.app_data::<ServerState>() {
+            Some(v) => v.clone(),
+            None => {
+                return Either::Left(future::ok(
                     sreq.into_response(
-                        HttpResponse::ServiceUnavailable()
-                            .body("0".to_owned())
+                        HttpResponse::InternalServerError()
+                            .body("Err: No State".to_owned())
                             .into_body(),
                     ),
                 ))
             }
-            _ => Either::Right(self.service.call(sreq)),
-        }
+        };
+        let (status, body) = match state.reject_ua.load().find(sreq.path(), &ua) {
+            Some(rule) => (rule.status, rule.body.clone()),
+            None => return Either::Right(self.service.call(sreq)),
+        };
+
+        debug!("Rejecting User-Agent: {:?}", ua);
+        Metrics::from(state.as_ref()).incr("error.rejectua");
+
+        Either::Left(future::ok(
+            sreq.into_response(HttpResponse::build(status).body(body).into_body()),
+        ))
     }
 }
 
-/// Determine if a User-Agent should be rejected w/ an error response.
-///
-/// firefox-ios < v20 suffers from a bug where our response headers
-/// can cause it to crash. They're sent an error response instead that
-/// avoids the crash.
-///
-/// Dev builds were originally labeled as v0 (or now "Firefox-iOS-Sync/dev") so
-/// we don't reject those.
-///
-/// https://github.com/mozilla-services/syncstorage-rs/issues/293
-fn should_reject(ua: &str) -> bool {
-    let major = IOS_UA_REGEX
-        .captures(ua)
-        .and_then(|captures| captures.name("major"))
-        .and_then(|major| major.as_str().parse::<u32>().ok())
-        .unwrap_or(20);
-    0 < major && major < 20
+#[cfg(test)]
+mod tests {
+    use super::RejectUARules;
+    use crate::settings::RejectUARule;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = RejectUARules::new(&RejectUARule::defaults()).unwrap();
+        let path = "/1.5/42/info/collections";
+        for ua in &[
+            "Firefox-iOS-Sync/18.0b1 (iPhone; iPhone OS 13.2.2) (Fennec (synctesting))",
+            "Firefox-iOS-Sync/1.0b1 (iPhone; iPhone OS 13.2.2) (Fennec (synctesting))",
+        ] {
+            assert!(rules.find(path, ua).is_some());
+        }
+        for ua in &[
+            "Firefox-iOS-Sync/23.0b17297 (iPhone; iPhone OS 12.4) (Firefox)",
+            "Firefox-iOS-Sync/0.0.1b1 (iPhone; iPhone OS 13.5) (Fennec (eoger))",
+            "Firefox-iOS-Sync/dev (iPhone; iPhone OS 13.5) (Fennec (eoger))",
+            "Firefox/75.0 FxSync/1.77.0.20200318210225.desktop",
+        ] {
+            assert!(rules.find(path, ua).is_none());
+        }
+    }
+
+    #[test]
+    fn test_configured_rules() {
+        let rule = RejectUARule {
+            regex: r"^Broken/(?P<v>\d+)".to_owned(),
+            version_capture: None,
+            min_version: None,
+            max_version: None,
+            status: 400,
+            body: "nope".to_owned(),
+            paths: vec![r"^/1\.5/\d+/storage/".to_owned()],
+        };
+        let rules = RejectUARules::new(&[rule.clone()]).unwrap();
+        let found = rules.find("/1.5/42/storage/tabs", "Broken/3").unwrap();
+        assert_eq!(found.status.as_u16(), 400);
+        assert_eq!(found.body, "nope");
+        assert!(rules.find("/1.5/42/info/collections", "Broken/3").is_none());
+
+        let rules = RejectUARules::new(&[RejectUARule {
+            version_capture: Some("v".to_owned()),
+            min_version: Some(3),
+            ..rule.clone()
+        }])
+        .unwrap();
+        assert!(rules.find("/1.5/42/storage/tabs", "Broken/2").is_none());
+        assert!(rules.find("/1.5/42/storage/tabs", "Broken/4").is_some());
+
+        assert!(RejectUARules::new(&[RejectUARule {
+            regex: "(".to_owned(),
+            ..rule
+        }])
+        .is_err());
+    }
 }
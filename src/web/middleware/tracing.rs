@@ -0,0 +1,91 @@
+//! A server span per request, continuing any trace from `traceparent`.
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures::future::{self, FutureExt, LocalBoxFuture, Ready};
+
+use crate::tracing::{self, Span, SpanContext, SpanKind};
+use crate::web::middleware::prometheus::endpoint;
+
+/// Middleware tracing each request's handling in a server span (when
+/// `tracing.enabled`).
+///
+/// The span's `SpanContext` is stored in the request's extensions, as the
+/// parent of the spans of its db transaction and calls.
+#[derive(Debug, Default)]
+pub struct Tracing;
+
+impl<S, B> Transform<S> for Tracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(TracingMiddleware { service })
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for TracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        if !tracing::enabled() {
+            return Box::pin(self.service.call(sreq));
+        }
+        let parent = sreq
+            .headers()
+            .get("traceparent")
+            .and_then(|header| header.to_str().ok())
+            .and_then(SpanContext::from_traceparent);
+        let method = sreq.method().to_string();
+        let mut span = Span::start(
+            &format!("{} {}", method, endpoint(sreq.path())),
+            SpanKind::Server,
+            parent.as_ref(),
+        );
+        span.set_attribute("http.method", method);
+        span.set_attribute("http.target", sreq.path());
+        if let Some(context) = span.context() {
+            sreq.extensions_mut().insert(context);
+        }
+
+        Box::pin(self.service.call(sreq).map(move |result| {
+            let status = match result {
+                Ok(ref resp) => resp.status(),
+                Err(ref e) => e.as_response_error().status_code(),
+            };
+            span.set_attribute("http.status_code", status.as_u16());
+            if status.is_server_error() {
+                span.set_error(status.canonical_reason().unwrap_or("Server error"));
+            }
+            result
+        }))
+    }
+}
@@ -1,5 +1,6 @@
 //! Web authentication, handlers, and middleware
 pub mod auth;
+pub mod cursor;
 pub mod error;
 pub mod extractors;
 pub mod handlers;
@@ -12,11 +13,13 @@ pub static X_LAST_MODIFIED: &str = "x-last-modified";
 pub static X_WEAVE_TIMESTAMP: &str = "x-weave-timestamp";
 pub static X_WEAVE_NEXT_OFFSET: &str = "x-weave-next-offset";
 pub static X_WEAVE_RECORDS: &str = "x-weave-records";
+pub static X_WEAVE_BACKOFF: &str = "x-weave-backoff";
 
 // Known DockerFlow commands for Ops callbacks
-pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
+pub const DOCKER_FLOW_ENDPOINTS: [&str; 5] = [
     "/__heartbeat__",
     "/__lbheartbeat__",
     "/__version__",
     "/__error__",
+    "/__metrics__",
 ];